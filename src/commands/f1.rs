@@ -1,9 +1,27 @@
-use crate::commands::util;
-use serde_json::Value;
-use serenity::builder::CreateEmbed;
+use crate::commands::registry::{SlashCommand, SubCommand, SubCommands};
+use crate::commands::{live, race_control, util};
+use crate::http::{self, HttpClient, HttpError};
+use crate::store;
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use serde_json::{json, Value};
+use serenity::async_trait;
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommandOption, CreateEmbed};
 use serenity::client::Context;
+use serenity::http::HttpError as DiscordHttpError;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::channel::ChannelType;
+use serenity::model::guild::{ScheduledEventStatus, ScheduledEventType};
+use serenity::model::id::{ChannelId, ScheduledEventId};
+use serenity::model::mention::Mentionable;
+use serenity::model::permissions::Permissions;
 use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+use serenity::model::Timestamp;
 use serenity::utils::{Colour, MessageBuilder};
+use serenity::Error as SerenityError;
+use std::collections::HashSet;
+
+/// Discord's error code for a scheduled event that was deleted or never existed
+const UNKNOWN_SCHEDULED_EVENT: isize = 10070;
 
 struct Standings {
     drivers: Option<MessageBuilder>,
@@ -18,15 +36,139 @@ struct SeasonCalendar {
     race_dates: MessageBuilder,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SessionKind {
+    FirstPractice,
+    SecondPractice,
+    ThirdPractice,
+    Qualifying,
+    Sprint,
+    Race,
+}
+
+impl SessionKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SessionKind::FirstPractice => "Practice 1",
+            SessionKind::SecondPractice => "Practice 2",
+            SessionKind::ThirdPractice => "Practice 3",
+            SessionKind::Qualifying => "Qualifying",
+            SessionKind::Sprint => "Sprint",
+            SessionKind::Race => "Race",
+        }
+    }
+
+    /// Short identifier used when remembering per-session state
+    pub fn key(&self) -> &'static str {
+        match self {
+            SessionKind::FirstPractice => "fp1",
+            SessionKind::SecondPractice => "fp2",
+            SessionKind::ThirdPractice => "fp3",
+            SessionKind::Qualifying => "qualifying",
+            SessionKind::Sprint => "sprint",
+            SessionKind::Race => "race",
+        }
+    }
+
+    /// Whether Ergast publishes results for this kind of session
+    pub fn has_results(&self) -> bool {
+        matches!(
+            self,
+            SessionKind::Qualifying | SessionKind::Sprint | SessionKind::Race
+        )
+    }
+
+    /// Rough length of the session, Ergast only provides start times
+    pub fn duration(&self) -> Duration {
+        match self {
+            SessionKind::Race => Duration::hours(2),
+            _ => Duration::hours(1),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Session {
+    pub kind: SessionKind,
+    pub start: DateTime<Utc>,
+}
+
+impl Session {
+    pub fn end(&self) -> DateTime<Utc> {
+        self.start + self.kind.duration()
+    }
+}
+
+/// A Grand Prix weekend from the season's calendar
+#[derive(Clone)]
+pub struct Race {
+    pub season: String,
+    pub round: String,
+    pub name: String,
+    pub circuit: String,
+    pub locality: String,
+    pub country: String,
+    pub sessions: Vec<Session>,
+}
+
+impl Race {
+    /// Identifies a session of this weekend, e.g. `2023-5-race`
+    pub fn session_key(&self, session: &Session) -> String {
+        format!("{}-{}-{}", self.season, self.round, session.kind.key())
+    }
+
+    /// Shortened name of the Grand Prix, e.g. `Italian GP`
+    pub fn short_name(&self) -> String {
+        self.name.replace("Grand Prix", "GP")
+    }
+
+    /// Shortened name with the host country's flag, e.g. `🇮🇹 Italian GP`
+    pub fn display_name(&self) -> String {
+        format!("{} {}", country_flag(&self.country), self.short_name())
+    }
+}
+
+/// Flag emoji for the countries Ergast uses in its calendar
+fn country_flag(country: &str) -> &'static str {
+    match country {
+        "Australia" => "🇦🇺",
+        "Austria" => "🇦🇹",
+        "Azerbaijan" => "🇦🇿",
+        "Bahrain" => "🇧🇭",
+        "Belgium" => "🇧🇪",
+        "Brazil" => "🇧🇷",
+        "Canada" => "🇨🇦",
+        "China" => "🇨🇳",
+        "France" => "🇫🇷",
+        "Germany" => "🇩🇪",
+        "Hungary" => "🇭🇺",
+        "Italy" => "🇮🇹",
+        "Japan" => "🇯🇵",
+        "Mexico" => "🇲🇽",
+        "Monaco" => "🇲🇨",
+        "Netherlands" => "🇳🇱",
+        "Portugal" => "🇵🇹",
+        "Qatar" => "🇶🇦",
+        "Russia" => "🇷🇺",
+        "Saudi Arabia" => "🇸🇦",
+        "Singapore" => "🇸🇬",
+        "Spain" => "🇪🇸",
+        "Turkey" => "🇹🇷",
+        "UAE" => "🇦🇪",
+        "UK" => "🇬🇧",
+        "USA" | "United States" => "🇺🇸",
+        _ => "🏁",
+    }
+}
+
 /// Collects json response from Ergast API call to get constructor standings
-async fn get_constructor_standings() -> Standings {
+async fn get_constructor_standings(http: &HttpClient) -> Result<Standings, HttpError> {
     let url = "https://ergast.com/api/f1/current/constructorStandings.json";
     let mut constructor_names = MessageBuilder::new();
     let mut constructor_points = MessageBuilder::new();
     let standings: Standings;
 
-    let data = reqwest::get(url).await.unwrap().text().await.unwrap();
-    let v: Value = serde_json::from_str(&data).unwrap();
+    let v: Value = http.get(url).await?.json().await?;
     let info = &v["MRData"]["StandingsTable"]["StandingsLists"][0]["ConstructorStandings"];
 
     for i in 0..10 {
@@ -43,19 +185,18 @@ async fn get_constructor_standings() -> Standings {
         points: Some(constructor_points),
     };
 
-    standings
+    Ok(standings)
 }
 
 /// Collects json response from Ergast API call to get driver standings
-async fn get_driver_standings() -> Standings {
+async fn get_driver_standings(http: &HttpClient) -> Result<Standings, HttpError> {
     let url = "https://ergast.com/api/f1/current/driverStandings.json";
     let mut driver_names = MessageBuilder::new();
     let mut driver_constructors = MessageBuilder::new();
     let mut driver_points = MessageBuilder::new();
     let standings: Standings;
 
-    let data = reqwest::get(url).await.unwrap().text().await.unwrap();
-    let v: Value = serde_json::from_str(&data).unwrap();
+    let v: Value = http.get(url).await?.json().await?;
     let info = &v["MRData"]["StandingsTable"]["StandingsLists"][0]["DriverStandings"];
 
     for i in 0..20 {
@@ -78,21 +219,20 @@ async fn get_driver_standings() -> Standings {
         points: Some(driver_points),
     };
 
-    standings
+    Ok(standings)
 }
 
 /// Collects json response from Ergast API call to get the season's calendar
-async fn get_season_calendar() -> SeasonCalendar {
+async fn get_season_calendar(http: &HttpClient) -> Result<SeasonCalendar, HttpError> {
     let url = "https://ergast.com/api/f1/current.json";
     let mut season_rounds = MessageBuilder::new();
     let mut race_names = MessageBuilder::new();
     let mut race_dates = MessageBuilder::new();
     let season_calendar: SeasonCalendar;
 
-    let data = reqwest::get(url).await.unwrap().text().await.unwrap();
-    let v: Value = serde_json::from_str(&data).unwrap();
+    let v: Value = http.get(url).await?.json().await?;
     let info = &v["MRData"]["RaceTable"]["Races"];
-    let total_rounds = get_total_rounds().await;
+    let total_rounds = get_total_rounds(http).await?;
     let current_season = get_season_year(&info);
 
     for i in 0..total_rounds as usize {
@@ -114,21 +254,94 @@ async fn get_season_calendar() -> SeasonCalendar {
         race_dates: race_dates,
     };
 
-    season_calendar
+    Ok(season_calendar)
+}
+
+/// Parses an Ergast `date`/`time` pair into a UTC timestamp
+fn parse_session_start(info: &Value) -> Option<DateTime<Utc>> {
+    let date = info["date"].as_str()?;
+    let time = info["time"]
+        .as_str()
+        .unwrap_or("00:00:00Z")
+        .trim_end_matches('Z');
+    let start =
+        NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%Y-%m-%d %H:%M:%S").ok()?;
+
+    Some(Utc.from_utc_datetime(&start))
+}
+
+/// Collects json response from Ergast API call to get every session of the season
+pub async fn get_races(http: &HttpClient) -> Result<Vec<Race>, HttpError> {
+    let url = "https://ergast.com/api/f1/current.json";
+    let session_fields = [
+        ("FirstPractice", SessionKind::FirstPractice),
+        ("SecondPractice", SessionKind::SecondPractice),
+        ("ThirdPractice", SessionKind::ThirdPractice),
+        ("Qualifying", SessionKind::Qualifying),
+        ("Sprint", SessionKind::Sprint),
+    ];
+
+    let v: Value = http.get(url).await?.json().await?;
+    let info = v["MRData"]["RaceTable"]["Races"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+
+    let races = info
+        .iter()
+        .map(|race| {
+            let mut sessions: Vec<Session> = session_fields
+                .iter()
+                .filter_map(|(field, kind)| {
+                    parse_session_start(&race[field]).map(|start| Session { kind: *kind, start })
+                })
+                .collect();
+            if let Some(start) = parse_session_start(race) {
+                sessions.push(Session {
+                    kind: SessionKind::Race,
+                    start,
+                });
+            }
+            sessions.sort_by_key(|session| session.start);
+
+            Race {
+                season: race["season"].as_str().unwrap_or_default().to_string(),
+                round: race["round"].as_str().unwrap_or_default().to_string(),
+                name: race["raceName"].as_str().unwrap_or_default().to_string(),
+                circuit: race["Circuit"]["circuitName"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                locality: race["Circuit"]["Location"]["locality"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                country: race["Circuit"]["Location"]["country"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                sessions,
+            }
+        })
+        .collect();
+
+    Ok(races)
 }
 
 /// Collects json response from Ergast API call to get the results from the most recent GP
-async fn get_recent_race_results() -> Standings {
+async fn get_recent_race_results(http: &HttpClient) -> Result<Standings, HttpError> {
     let url = "https://ergast.com/api/f1/current/last/results.json";
     let mut driver_names = MessageBuilder::new();
     let mut driver_constructors = MessageBuilder::new();
     let mut driver_points = MessageBuilder::new();
     let standings: Standings;
 
-    let data = reqwest::get(url).await.unwrap().text().await.unwrap();
-    let v: Value = serde_json::from_str(&data).unwrap();
+    let v: Value = http.get(url).await?.json().await?;
     let info = &v["MRData"]["RaceTable"]["Races"][0]["Results"];
-    let total_drivers: u8 = v["MRData"]["total"].as_str().unwrap().parse().unwrap();
+    let total_drivers: u8 = v["MRData"]["total"]
+        .as_str()
+        .and_then(|total| total.parse().ok())
+        .unwrap_or_default();
 
     for i in 0..total_drivers as usize {
         let points = info[i]["points"].to_string().replace("\"", "");
@@ -148,42 +361,304 @@ async fn get_recent_race_results() -> Standings {
         points: Some(driver_points),
     };
 
-    standings
+    Ok(standings)
+}
+
+/// Collects the results of a weekend's session, `None` until Ergast has published them
+pub async fn get_session_results(
+    http: &HttpClient,
+    race: &Race,
+    kind: SessionKind,
+) -> Option<CreateEmbed> {
+    let (endpoint, table) = match kind {
+        SessionKind::Race => ("results", "Results"),
+        SessionKind::Sprint => ("sprint", "SprintResults"),
+        SessionKind::Qualifying => ("qualifying", "QualifyingResults"),
+        _ => return None,
+    };
+    let url = format!(
+        "https://ergast.com/api/f1/{}/{}/{}.json",
+        race.season, race.round, endpoint
+    );
+    let mut driver_names = MessageBuilder::new();
+    let mut driver_constructors = MessageBuilder::new();
+    let mut driver_results = MessageBuilder::new();
+
+    let v: Value = http.get(&url).await.ok()?.json().await.ok()?;
+    let info = v["MRData"]["RaceTable"]["Races"][0][table].as_array()?;
+    if info.is_empty() {
+        return None;
+    }
+
+    for result in info {
+        let driver = result["Driver"]["familyName"].as_str().unwrap_or_default();
+        let constructor = result["Constructor"]["name"].as_str().unwrap_or_default();
+        // Qualifying has no points, show the driver's best lap from the furthest part reached
+        let outcome = match kind {
+            SessionKind::Qualifying => result["Q3"]
+                .as_str()
+                .or_else(|| result["Q2"].as_str())
+                .or_else(|| result["Q1"].as_str())
+                .unwrap_or("-"),
+            _ => result["points"].as_str().unwrap_or_default(),
+        };
+
+        driver_names.push(format!("{}\n", driver));
+        driver_constructors.push(format!("{}\n", constructor));
+        driver_results.push(format!("{}\n", outcome));
+    }
+
+    let mut embed = CreateEmbed::default();
+    embed.title(format!("{} {} Results", race.name, kind.name()));
+    embed.colour(Colour::DARK_RED);
+    embed.thumbnail("https://1000logos.net/wp-content/uploads/2020/02/F1-Logo-500x281.png");
+    embed.field("Name", driver_names, true);
+    embed.field("Constructor", driver_constructors, true);
+    match kind {
+        SessionKind::Qualifying => embed.field("Best Lap", driver_results, true),
+        _ => embed.field("Points", driver_results, true),
+    };
+
+    Some(embed)
+}
+
+/// Current driver standings as compact JSON, for the AI to answer questions with
+pub async fn get_driver_standings_json(http: &HttpClient) -> Result<Value, HttpError> {
+    let url = "https://ergast.com/api/f1/current/driverStandings.json";
+    let v: Value = http.get(url).await?.json().await?;
+    let list = &v["MRData"]["StandingsTable"]["StandingsLists"][0];
+
+    let standings: Vec<Value> = list["DriverStandings"]
+        .as_array()
+        .map(|standings| {
+            standings
+                .iter()
+                .map(|standing| {
+                    json!({
+                        "position": standing["position"],
+                        "driver": format!(
+                            "{} {}",
+                            standing["Driver"]["givenName"].as_str().unwrap_or_default(),
+                            standing["Driver"]["familyName"].as_str().unwrap_or_default()
+                        ),
+                        "constructor": standing["Constructors"][0]["name"],
+                        "points": standing["points"],
+                        "wins": standing["wins"]
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(json!({
+        "season": list["season"],
+        "after_round": list["round"],
+        "standings": standings
+    }))
+}
+
+/// Current constructor standings as compact JSON, for the AI to answer questions with
+pub async fn get_constructor_standings_json(http: &HttpClient) -> Result<Value, HttpError> {
+    let url = "https://ergast.com/api/f1/current/constructorStandings.json";
+    let v: Value = http.get(url).await?.json().await?;
+    let list = &v["MRData"]["StandingsTable"]["StandingsLists"][0];
+
+    let standings: Vec<Value> = list["ConstructorStandings"]
+        .as_array()
+        .map(|standings| {
+            standings
+                .iter()
+                .map(|standing| {
+                    json!({
+                        "position": standing["position"],
+                        "constructor": standing["Constructor"]["name"],
+                        "points": standing["points"],
+                        "wins": standing["wins"]
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(json!({
+        "season": list["season"],
+        "after_round": list["round"],
+        "standings": standings
+    }))
+}
+
+/// The season's races and their session times as compact JSON, for the AI to answer questions with
+pub async fn get_calendar_json(http: &HttpClient) -> Result<Value, HttpError> {
+    let races: Vec<Value> = get_races(http)
+        .await?
+        .iter()
+        .map(|race| {
+            let sessions: Vec<Value> = race
+                .sessions
+                .iter()
+                .map(|session| json!({ "session": session.kind.name(), "start": session.start }))
+                .collect();
+            json!({
+                "round": race.round,
+                "name": race.name,
+                "circuit": race.circuit,
+                "location": format!("{}, {}", race.locality, race.country),
+                "sessions": sessions
+            })
+        })
+        .collect();
+
+    Ok(json!({ "races": races }))
+}
+
+/// Results of the most recent race as compact JSON, for the AI to answer questions with
+pub async fn get_last_race_results_json(http: &HttpClient) -> Result<Value, HttpError> {
+    let url = "https://ergast.com/api/f1/current/last/results.json";
+    let v: Value = http.get(url).await?.json().await?;
+    let race = &v["MRData"]["RaceTable"]["Races"][0];
+
+    let results: Vec<Value> = race["Results"]
+        .as_array()
+        .map(|results| {
+            results
+                .iter()
+                .map(|result| {
+                    json!({
+                        "position": result["position"],
+                        "driver": format!(
+                            "{} {}",
+                            result["Driver"]["givenName"].as_str().unwrap_or_default(),
+                            result["Driver"]["familyName"].as_str().unwrap_or_default()
+                        ),
+                        "constructor": result["Constructor"]["name"],
+                        "grid": result["grid"],
+                        "status": result["status"],
+                        "points": result["points"]
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(json!({
+        "season": race["season"],
+        "round": race["round"],
+        "race": race["raceName"],
+        "date": race["date"],
+        "results": results
+    }))
 }
 
 /// Return the total amount of races for the current season
-async fn get_total_rounds() -> u8 {
+async fn get_total_rounds(http: &HttpClient) -> Result<u8, HttpError> {
     let url = "https://ergast.com/api/f1/current.json";
 
-    let data = reqwest::get(url).await.unwrap().text().await.unwrap();
-    let v: Value = serde_json::from_str(&data).unwrap();
-    let total: u8 = v["MRData"]["total"].as_str().unwrap().parse().unwrap();
-    total
+    let v: Value = http.get(url).await?.json().await?;
+    let total: u8 = v["MRData"]["total"]
+        .as_str()
+        .and_then(|total| total.parse().ok())
+        .unwrap_or_default();
+    Ok(total)
 }
 
 /// Returns the name of the most recent GP
-async fn get_recent_race_name() -> String {
+async fn get_recent_race_name(http: &HttpClient) -> Result<String, HttpError> {
     let url = "https://ergast.com/api/f1/current/last/results.json";
 
-    let data = reqwest::get(url).await.unwrap().text().await.unwrap();
-    let v: Value = serde_json::from_str(&data).unwrap();
+    let v: Value = http.get(url).await?.json().await?;
 
     let race_name = v["MRData"]["RaceTable"]["Races"][0]["raceName"]
         .as_str()
-        .unwrap()
+        .unwrap_or_default()
         .to_string();
-    race_name
+    Ok(race_name)
 }
 
 /// Returns the most recent season's year
 fn get_season_year(info: &Value) -> String {
-    info[0]["season"].as_str().unwrap().to_string()
+    info[0]["season"].as_str().unwrap_or_default().to_string()
+}
+
+/// `/f1`, standings, results and everything that follows a race weekend
+pub struct F1 {
+    subcommands: SubCommands,
+}
+
+impl F1 {
+    pub fn new() -> F1 {
+        F1 {
+            subcommands: SubCommands::new(vec![
+                Box::new(Constructors),
+                Box::new(Drivers),
+                Box::new(Calendar),
+                Box::new(RecentRaceResults),
+                Box::new(SyncEvents),
+                Box::new(WeekendThreads),
+                Box::new(live::group()),
+                Box::new(race_control::group()),
+            ]),
+        }
+    }
+}
+
+impl Default for F1 {
+    fn default() -> F1 {
+        F1::new()
+    }
+}
+
+#[async_trait]
+impl SlashCommand for F1 {
+    fn name(&self) -> &str {
+        "f1"
+    }
+
+    fn description(&self) -> &str {
+        "Get the current F1 standings and calendar"
+    }
+
+    fn options(&self, command: &mut CreateApplicationCommand) {
+        self.subcommands.register(command);
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        self.subcommands.run(0, ctx, command).await
+    }
+}
+
+/// `/f1 constructors`
+pub struct Constructors;
+
+#[async_trait]
+impl SubCommand for Constructors {
+    fn name(&self) -> &str {
+        "constructors"
+    }
+
+    fn description(&self) -> &str {
+        "Get current constructor standings"
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        constructor_standings(ctx, command).await
+    }
 }
 
 /// Retrieves F1 constructor standings and outputs results through an embedded message
 pub async fn constructor_standings(ctx: Context, command: ApplicationCommandInteraction) {
     // Collect constructor info
-    let standings = get_constructor_standings().await;
+    let http = http::get(&ctx).await;
+    let (standings, deferred) =
+        util::defer_if_slow(&ctx, &command, get_constructor_standings(&http)).await;
+    let standings = match standings {
+        Ok(standings) => standings,
+        Err(why) => {
+            println!("Cannot fetch constructor standings: {}", why);
+            let content = "Could not reach the F1 data :(".to_string();
+            util::generate_or_edit_message(ctx, command, content, deferred).await;
+            return;
+        }
+    };
 
     // Format embedded message
     let mut embed = CreateEmbed::default();
@@ -197,13 +672,42 @@ pub async fn constructor_standings(ctx: Context, command: ApplicationCommandInte
     });
 
     // Attempt to send response
-    util::generate_embed_message(ctx, command, embed).await
+    util::generate_or_edit_embed_message(ctx, command, embed, deferred).await
+}
+
+/// `/f1 drivers`
+pub struct Drivers;
+
+#[async_trait]
+impl SubCommand for Drivers {
+    fn name(&self) -> &str {
+        "drivers"
+    }
+
+    fn description(&self) -> &str {
+        "Get current driver standings"
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        driver_standings(ctx, command).await
+    }
 }
 
 /// Retrieves F1 driver standings and outputs results through an embedded message
 pub async fn driver_standings(ctx: Context, command: ApplicationCommandInteraction) {
     // Collect driver info
-    let standings = get_driver_standings().await;
+    let http = http::get(&ctx).await;
+    let (standings, deferred) =
+        util::defer_if_slow(&ctx, &command, get_driver_standings(&http)).await;
+    let standings = match standings {
+        Ok(standings) => standings,
+        Err(why) => {
+            println!("Cannot fetch driver standings: {}", why);
+            let content = "Could not reach the F1 data :(".to_string();
+            util::generate_or_edit_message(ctx, command, content, deferred).await;
+            return;
+        }
+    };
 
     // Format embedded message
     let mut embed = CreateEmbed::default();
@@ -218,12 +722,41 @@ pub async fn driver_standings(ctx: Context, command: ApplicationCommandInteracti
     });
 
     // Attempt to send response
-    util::generate_embed_message(ctx, command, embed).await
+    util::generate_or_edit_embed_message(ctx, command, embed, deferred).await
+}
+
+/// `/f1 calendar`
+pub struct Calendar;
+
+#[async_trait]
+impl SubCommand for Calendar {
+    fn name(&self) -> &str {
+        "calendar"
+    }
+
+    fn description(&self) -> &str {
+        "Get the season's calendar"
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        season_calendar(ctx, command).await
+    }
 }
 
 pub async fn season_calendar(ctx: Context, command: ApplicationCommandInteraction) {
     // Collect season info
-    let calendar = get_season_calendar().await;
+    let http = http::get(&ctx).await;
+    let (calendar, deferred) =
+        util::defer_if_slow(&ctx, &command, get_season_calendar(&http)).await;
+    let calendar = match calendar {
+        Ok(calendar) => calendar,
+        Err(why) => {
+            println!("Cannot fetch season calendar: {}", why);
+            let content = "Could not reach the F1 data :(".to_string();
+            util::generate_or_edit_message(ctx, command, content, deferred).await;
+            return;
+        }
+    };
 
     // Format embedded message
     let mut embed = CreateEmbed::default();
@@ -238,13 +771,46 @@ pub async fn season_calendar(ctx: Context, command: ApplicationCommandInteractio
     });
 
     // Attempt to send response
-    util::generate_embed_message(ctx, command, embed).await
+    util::generate_or_edit_embed_message(ctx, command, embed, deferred).await
+}
+
+/// `/f1 recent_race_results`
+pub struct RecentRaceResults;
+
+#[async_trait]
+impl SubCommand for RecentRaceResults {
+    fn name(&self) -> &str {
+        "recent_race_results"
+    }
+
+    fn description(&self) -> &str {
+        "Get the results from the most recent Grand Prix"
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        recent_race_results(ctx, command).await
+    }
 }
 
 pub async fn recent_race_results(ctx: Context, command: ApplicationCommandInteraction) {
     // Collect the last race's results
-    let standings = get_recent_race_results().await;
-    let race_name = get_recent_race_name().await;
+    let http = http::get(&ctx).await;
+    let (results, deferred) = util::defer_if_slow(&ctx, &command, async {
+        Ok::<_, HttpError>((
+            get_recent_race_results(&http).await?,
+            get_recent_race_name(&http).await?,
+        ))
+    })
+    .await;
+    let (standings, race_name) = match results {
+        Ok(results) => results,
+        Err(why) => {
+            println!("Cannot fetch recent race results: {}", why);
+            let content = "Could not reach the F1 data :(".to_string();
+            util::generate_or_edit_message(ctx, command, content, deferred).await;
+            return;
+        }
+    };
 
     // Format embedded message
     let mut embed = CreateEmbed::default();
@@ -259,5 +825,295 @@ pub async fn recent_race_results(ctx: Context, command: ApplicationCommandIntera
     });
 
     // Attempt to send response
-    util::generate_embed_message(ctx, command, embed).await
+    util::generate_or_edit_embed_message(ctx, command, embed, deferred).await
+}
+
+/// `/f1 sync_events`
+pub struct SyncEvents;
+
+#[async_trait]
+impl SubCommand for SyncEvents {
+    fn name(&self) -> &str {
+        "sync_events"
+    }
+
+    fn description(&self) -> &str {
+        "Create server events for the remaining Grands Prix"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option.create_sub_option(|sub_option| {
+            sub_option
+                .name("qualifying")
+                .description("Also create events for qualifying sessions")
+                .kind(CommandOptionType::Boolean)
+        });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        sync_events(ctx, command).await
+    }
+}
+
+/// Creates or updates a guild scheduled event for every remaining race (and optionally qualifying)
+pub async fn sync_events(ctx: Context, command: ApplicationCommandInteraction) {
+    let guild_id = match command.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            util::generate_message(
+                ctx,
+                command,
+                "Events can only be synced in a server".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    if !util::has_permission(&command, Permissions::MANAGE_EVENTS) {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "You need the Manage Events permission to sync events".to_string(),
+        )
+        .await;
+        return;
+    }
+
+    let include_qualifying = util::get_sub_option(&command, "qualifying")
+        .and_then(|value| value.as_bool())
+        .unwrap_or(false);
+
+    // Creating a few dozen events can take longer than the 3 seconds Discord gives us
+    if !util::defer(&ctx, &command, false).await {
+        return;
+    }
+
+    let http = http::get(&ctx).await;
+    let races = match get_races(&http).await {
+        Ok(races) => races,
+        Err(why) => {
+            println!("Cannot fetch calendar: {}", why);
+            util::edit_generated_message(
+                ctx,
+                command,
+                "Could not fetch the calendar :(".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    let store = store::get(&ctx).await;
+    let now = Utc::now();
+    let (mut created, mut updated, mut failed) = (0, 0, 0);
+
+    // Forget events that were deleted or have finished so the stored ids don't pile up
+    match guild_id.scheduled_events(&ctx.http, false).await {
+        Ok(events) => {
+            let upcoming: HashSet<ScheduledEventId> = events
+                .iter()
+                .filter(|event| {
+                    matches!(
+                        event.status,
+                        ScheduledEventStatus::Scheduled | ScheduledEventStatus::Active
+                    )
+                })
+                .map(|event| event.id)
+                .collect();
+            store
+                .write(|data| {
+                    if let Some(events) = data.scheduled_events.get_mut(&guild_id) {
+                        events.retain(|_, event_id| upcoming.contains(event_id));
+                    }
+                })
+                .await;
+        }
+        Err(why) => println!("Cannot fetch scheduled events: {}", why),
+    }
+
+    for race in &races {
+        let sessions = race.sessions.iter().filter(|session| {
+            session.start > now
+                && (session.kind == SessionKind::Race
+                    || (include_qualifying && session.kind == SessionKind::Qualifying))
+        });
+
+        for session in sessions {
+            let key = race.session_key(session);
+            let name = format!("{} - {}", race.name, session.kind.name());
+            let description = format!("Round {} of the {} season", race.round, race.season);
+            let location = format!("{}, {}, {}", race.circuit, race.locality, race.country);
+            let start = Timestamp::from_unix_timestamp(session.start.timestamp())
+                .expect("Expected a valid session start");
+            let end = Timestamp::from_unix_timestamp(session.end().timestamp())
+                .expect("Expected a valid session end");
+
+            let existing = store
+                .read(|data| {
+                    data.scheduled_events
+                        .get(&guild_id)
+                        .and_then(|events| events.get(&key))
+                        .copied()
+                })
+                .await;
+
+            if let Some(event_id) = existing {
+                let edited = guild_id
+                    .edit_scheduled_event(&ctx.http, event_id, |event| {
+                        event
+                            .name(&name)
+                            .description(&description)
+                            .location(&location)
+                            .start_time(start)
+                            .end_time(end)
+                    })
+                    .await;
+
+                match edited {
+                    Ok(_) => {
+                        updated += 1;
+                        continue;
+                    }
+                    // Deleted by hand since the ids were pruned, so it is created again
+                    Err(why) if is_unknown_event(&why) => {}
+                    // Anything else, such as a timeout, would leave a duplicate behind
+                    Err(why) => {
+                        println!("Cannot update scheduled event: {}", why);
+                        failed += 1;
+                        continue;
+                    }
+                }
+            }
+
+            let event = guild_id
+                .create_scheduled_event(&ctx.http, |event| {
+                    event
+                        .name(&name)
+                        .description(&description)
+                        .kind(ScheduledEventType::External)
+                        .location(&location)
+                        .start_time(start)
+                        .end_time(end)
+                })
+                .await;
+
+            match event {
+                Ok(event) => {
+                    store
+                        .write(|data| {
+                            data.scheduled_events
+                                .entry(guild_id)
+                                .or_default()
+                                .insert(key, event.id)
+                        })
+                        .await;
+                    created += 1;
+                }
+                Err(why) => {
+                    println!("Cannot create scheduled event: {}", why);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    let mut summary = format!(
+        "Created {} and updated {} scheduled events",
+        created, updated
+    );
+    if failed > 0 {
+        summary.push_str(&format!(", {} could not be synced", failed));
+    }
+
+    util::edit_generated_message(ctx, command, summary).await
+}
+
+/// Whether Discord answered that a scheduled event doesn't exist
+fn is_unknown_event(why: &SerenityError) -> bool {
+    match why {
+        SerenityError::Http(error) => matches!(
+            error.as_ref(),
+            DiscordHttpError::UnsuccessfulRequest(response)
+                if response.error.code == UNKNOWN_SCHEDULED_EVENT
+        ),
+        _ => false,
+    }
+}
+
+/// `/f1 weekend_threads`
+pub struct WeekendThreads;
+
+#[async_trait]
+impl SubCommand for WeekendThreads {
+    fn name(&self) -> &str {
+        "weekend_threads"
+    }
+
+    fn description(&self) -> &str {
+        "Open a discussion thread for every session of a race weekend"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option.create_sub_option(|sub_option| {
+            sub_option
+                .name("channel")
+                .description("Where to open the threads, leave empty to turn them off")
+                .kind(CommandOptionType::Channel)
+                .channel_types(&[ChannelType::Text, ChannelType::Forum])
+        });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        weekend_threads(ctx, command).await
+    }
+}
+
+/// Sets the channel race weekend discussion threads are opened in, or turns them off
+pub async fn weekend_threads(ctx: Context, command: ApplicationCommandInteraction) {
+    let guild_id = match command.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            util::generate_message(
+                ctx,
+                command,
+                "Weekend threads can only be set up in a server".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    if !util::has_permission(&command, Permissions::MANAGE_THREADS) {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "You need the Manage Threads permission to set up weekend threads".to_string(),
+        )
+        .await;
+        return;
+    }
+
+    let channel_id = util::get_sub_option(&command, "channel")
+        .and_then(|value| value.as_str())
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(ChannelId);
+
+    let store = store::get(&ctx).await;
+    store
+        .write(|data| match channel_id {
+            Some(channel_id) => data.weekend_threads.insert(guild_id, channel_id),
+            None => data.weekend_threads.remove(&guild_id),
+        })
+        .await;
+
+    let content = match channel_id {
+        Some(channel_id) => format!(
+            "Race weekend threads will be opened in {}",
+            channel_id.mention()
+        ),
+        None => "Race weekend threads are turned off".to_string(),
+    };
+
+    util::generate_message(ctx, command, content).await
 }
@@ -0,0 +1,52 @@
+use crate::commands::f1;
+use crate::http::HttpClient;
+use crate::llm::{Tool, ToolCall};
+use serde_json::json;
+
+/// Functions the AI can call to answer F1 questions with current data instead of guessing
+pub fn tools() -> Vec<Tool> {
+    let no_parameters = || json!({ "type": "object", "properties": {} });
+
+    vec![
+        Tool {
+            name: "driver_standings",
+            description: "Get the current Formula 1 drivers' championship standings",
+            parameters: no_parameters(),
+        },
+        Tool {
+            name: "constructor_standings",
+            description: "Get the current Formula 1 constructors' championship standings",
+            parameters: no_parameters(),
+        },
+        Tool {
+            name: "calendar",
+            description:
+                "Get this season's Formula 1 calendar with the start time of every session in UTC",
+            parameters: no_parameters(),
+        },
+        Tool {
+            name: "last_race_results",
+            description: "Get the results of the most recent Formula 1 race",
+            parameters: no_parameters(),
+        },
+    ]
+}
+
+/// Runs a tool call and returns its result as JSON text for the AI to read
+pub async fn call(http: &HttpClient, tool_call: &ToolCall) -> String {
+    let result = match tool_call.function.name.as_str() {
+        "driver_standings" => f1::get_driver_standings_json(http).await,
+        "constructor_standings" => f1::get_constructor_standings_json(http).await,
+        "calendar" => f1::get_calendar_json(http).await,
+        "last_race_results" => f1::get_last_race_results_json(http).await,
+        name => return json!({ "error": format!("Unknown tool {}", name) }).to_string(),
+    };
+
+    match result {
+        Ok(result) => result.to_string(),
+        Err(why) => {
+            println!("Cannot run AI tool {}: {}", tool_call.function.name, why);
+            json!({ "error": "The F1 data could not be fetched" }).to_string()
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use crate::commands::registry::{Group, SubCommand, SubCommands};
+use crate::commands::util;
+use crate::http::{self, HttpClient, HttpError};
+use crate::openf1::{self, RaceControl, SessionInfo};
+use chrono::{DateTime, Duration, Utc};
+use serde_json::Value;
+use serenity::async_trait;
+use serenity::builder::CreateEmbed;
+use serenity::client::Context;
+use serenity::model::id::{ChannelId, MessageId};
+use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+use serenity::prelude::{Mutex, TypeMapKey};
+use serenity::utils::{Colour, MessageBuilder};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time;
+
+/// How often the feed is polled and the tracker message edited
+const POLL_INTERVAL: time::Duration = time::Duration::from_secs(5);
+/// Trackers stop on their own after this long in case the session end is never seen
+const MAX_RUNTIME_HOURS: i64 = 4;
+
+/// Live trackers currently running, one per channel. Channels whose tracker is still starting are
+/// reserved without a handle.
+pub struct LiveTrackers;
+
+impl TypeMapKey for LiveTrackers {
+    type Value = Arc<Mutex<HashMap<ChannelId, Option<tokio::task::JoinHandle<()>>>>>;
+}
+
+/// Everything known about the session so far, built up from the feed's updates
+#[derive(Default)]
+struct Timing {
+    drivers: HashMap<u32, String>,
+    positions: HashMap<u32, u32>,
+    laps: HashMap<u32, u32>,
+    gaps: HashMap<u32, String>,
+    flag: Option<String>,
+    safety_car: Option<String>,
+    chequered: bool,
+    last_position: Option<DateTime<Utc>>,
+    last_lap: Option<DateTime<Utc>>,
+    last_interval: Option<DateTime<Utc>>,
+    last_race_control: Option<DateTime<Utc>>,
+}
+
+impl Timing {
+    /// Fetches whatever happened since the previous poll
+    async fn poll(&mut self, http: &HttpClient, session_key: &str) -> Result<(), HttpError> {
+        if self.drivers.is_empty() {
+            for driver in openf1::get_drivers(http, session_key).await? {
+                self.drivers
+                    .insert(driver.driver_number, driver.name_acronym);
+            }
+        }
+
+        for position in openf1::get_positions(http, session_key, self.last_position).await? {
+            self.positions
+                .insert(position.driver_number, position.position);
+            self.last_position = self.last_position.max(Some(position.date));
+        }
+
+        for lap in openf1::get_laps(http, session_key, self.last_lap).await? {
+            self.laps.insert(lap.driver_number, lap.lap_number);
+            self.last_lap = self.last_lap.max(lap.date_start);
+        }
+
+        for interval in openf1::get_intervals(http, session_key, self.last_interval).await? {
+            let gap = match interval.gap_to_leader {
+                Some(Value::Number(gap)) => format!("+{:.3}", gap.as_f64().unwrap_or_default()),
+                Some(Value::String(gap)) => gap,
+                _ => continue,
+            };
+            self.gaps.insert(interval.driver_number, gap);
+            self.last_interval = self.last_interval.max(Some(interval.date));
+        }
+
+        for message in openf1::get_race_control(http, session_key, self.last_race_control).await? {
+            self.last_race_control = self.last_race_control.max(Some(message.date));
+            self.apply_race_control(&message);
+        }
+
+        Ok(())
+    }
+
+    /// Tracks track-wide flags and safety car periods
+    fn apply_race_control(&mut self, message: &RaceControl) {
+        match message.category.as_str() {
+            "Flag" if message.scope.as_deref() == Some("Track") => {
+                let flag = message.flag.clone().unwrap_or_default();
+                if flag == "CHEQUERED" {
+                    self.chequered = true;
+                }
+                if flag == "GREEN" {
+                    self.safety_car = None;
+                }
+                self.flag = Some(flag);
+            }
+            "SafetyCar" => {
+                let text = message.message.to_uppercase();
+                if text.contains("ENDING") || text.contains("IN THIS LAP") {
+                    self.safety_car = None;
+                } else if text.contains("VIRTUAL SAFETY CAR") {
+                    self.safety_car = Some("Virtual Safety Car".to_string());
+                } else if text.contains("SAFETY CAR") {
+                    self.safety_car = Some("Safety Car".to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn embed(&self, session: &SessionInfo, finished: bool) -> CreateEmbed {
+        let mut order: Vec<(&u32, &u32)> = self.positions.iter().collect();
+        order.sort_by_key(|(_, position)| **position);
+
+        let mut positions = MessageBuilder::new();
+        let mut drivers = MessageBuilder::new();
+        let mut gaps = MessageBuilder::new();
+
+        for (driver_number, position) in order.into_iter().take(10) {
+            let name = self
+                .drivers
+                .get(driver_number)
+                .cloned()
+                .unwrap_or_else(|| format!("#{}", driver_number));
+            let gap = match position {
+                1 => "Leader".to_string(),
+                _ => self.gaps.get(driver_number).cloned().unwrap_or_default(),
+            };
+
+            positions.push(format!("{}\n", position));
+            drivers.push(format!("{}\n", name));
+            gaps.push(format!("{}\n", gap));
+        }
+
+        let lap = self.laps.values().max().copied().unwrap_or_default();
+        let status = if finished { "Finished" } else { "LIVE" };
+
+        let mut embed = CreateEmbed::default();
+        embed.title(format!(
+            "{} {} — {}",
+            session.location.as_deref().unwrap_or_default(),
+            session.session_name,
+            status
+        ));
+        embed.colour(Colour::DARK_RED);
+        embed.thumbnail("https://1000logos.net/wp-content/uploads/2020/02/F1-Logo-500x281.png");
+        if lap > 0 {
+            embed.description(format!("Lap {}", lap));
+        }
+        if !self.positions.is_empty() {
+            embed.field("Pos", positions, true);
+            embed.field("Driver", drivers, true);
+            embed.field("Gap", gaps, true);
+        }
+        embed.field(
+            "Flag",
+            self.flag.as_deref().unwrap_or("GREEN").to_string(),
+            true,
+        );
+        embed.field(
+            "Safety Car",
+            self.safety_car.as_deref().unwrap_or("None").to_string(),
+            true,
+        );
+        embed
+            .footer(|footer| footer.text(format!("Updated {}", Utc::now().format("%H:%M:%S UTC"))));
+
+        embed
+    }
+}
+
+/// Keeps editing the tracker message until the session finishes
+async fn track(ctx: Context, channel_id: ChannelId, message_id: MessageId, session: SessionInfo) {
+    let session_key = session.session_key.to_string();
+    let stop_at = Utc::now() + Duration::hours(MAX_RUNTIME_HOURS);
+    let http = http::get(&ctx).await;
+    let mut timing = Timing::default();
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(why) = timing.poll(&http, &session_key).await {
+            println!("Cannot poll live timing: {}", why);
+            continue;
+        }
+
+        // Races end with the chequered flag, other sessions wave it several times so use the end time
+        let now = Utc::now();
+        let finished = match session.session_type.as_str() {
+            "Race" => timing.chequered,
+            _ => session.date_end.is_some_and(|end| now >= end),
+        } || now >= stop_at;
+
+        let embed = timing.embed(&session, finished);
+        if let Err(why) = channel_id
+            .edit_message(&ctx.http, message_id, |message| message.set_embed(embed))
+            .await
+        {
+            println!("Cannot edit live tracker: {}", why);
+        }
+
+        if finished {
+            break;
+        }
+    }
+
+    let trackers = get_trackers(&ctx).await;
+    trackers.lock().await.remove(&channel_id);
+}
+
+async fn get_trackers(
+    ctx: &Context,
+) -> Arc<Mutex<HashMap<ChannelId, Option<tokio::task::JoinHandle<()>>>>> {
+    ctx.data
+        .read()
+        .await
+        .get::<LiveTrackers>()
+        .expect("Expected LiveTrackers in TypeMap")
+        .clone()
+}
+
+/// `/f1 live start`
+pub struct Start;
+
+#[async_trait]
+impl SubCommand for Start {
+    fn name(&self) -> &str {
+        "start"
+    }
+
+    fn description(&self) -> &str {
+        "Post a continuously updated live timing message"
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        start(ctx, command).await
+    }
+}
+
+/// `/f1 live`
+pub fn group() -> Group {
+    Group {
+        name: "live",
+        description: "Follow the current session live",
+        subcommands: SubCommands::new(vec![Box::new(Start), Box::new(Stop)]),
+    }
+}
+
+/// Starts following the current session in the channel the command was used in
+pub async fn start(ctx: Context, command: ApplicationCommandInteraction) {
+    let trackers = get_trackers(&ctx).await;
+    // Reserve the channel right away so a second start can't slip in while this one is starting
+    let reserved = match trackers.lock().await.entry(command.channel_id) {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(entry) => {
+            entry.insert(None);
+            true
+        }
+    };
+    if !reserved {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "A live tracker is already running in this channel".to_string(),
+        )
+        .await;
+        return;
+    }
+
+    let http = http::get(&ctx).await;
+    let (session, deferred) =
+        util::defer_if_slow(&ctx, &command, openf1::get_session(&http, "latest")).await;
+    let session = match session {
+        Ok(Some(session)) => session,
+        Ok(None) => {
+            trackers.lock().await.remove(&command.channel_id);
+            let content = "No session found to follow".to_string();
+            util::generate_or_edit_message(ctx, command, content, deferred).await;
+            return;
+        }
+        Err(why) => {
+            println!("Cannot fetch live session: {}", why);
+            trackers.lock().await.remove(&command.channel_id);
+            let content = "Could not reach the live timing feed :(".to_string();
+            util::generate_or_edit_message(ctx, command, content, deferred).await;
+            return;
+        }
+    };
+
+    // Interaction responses can only be edited for 15 minutes, so the tracker is a regular message
+    util::generate_or_edit_message(
+        ctx.to_owned(),
+        command.to_owned(),
+        format!("Following {} live!", session.session_name),
+        deferred,
+    )
+    .await;
+
+    let message = command
+        .channel_id
+        .send_message(&ctx.http, |message| {
+            message.set_embed(Timing::default().embed(&session, false))
+        })
+        .await;
+
+    // Hold the lock until the handle is stored so a tracker that ends early can't race it
+    let mut trackers = trackers.lock().await;
+    match message {
+        // The reservation is gone when the tracker was stopped while it was starting
+        Ok(message) => {
+            if let Some(reservation) = trackers.get_mut(&command.channel_id) {
+                let handle =
+                    tokio::spawn(track(ctx.clone(), command.channel_id, message.id, session));
+                *reservation = Some(handle);
+            }
+        }
+        Err(why) => {
+            println!("Cannot post live tracker: {}", why);
+            trackers.remove(&command.channel_id);
+        }
+    }
+}
+
+/// `/f1 live stop`
+pub struct Stop;
+
+#[async_trait]
+impl SubCommand for Stop {
+    fn name(&self) -> &str {
+        "stop"
+    }
+
+    fn description(&self) -> &str {
+        "Stop the live timing message in this channel"
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        stop(ctx, command).await
+    }
+}
+
+/// Stops the live tracker running in the channel the command was used in
+pub async fn stop(ctx: Context, command: ApplicationCommandInteraction) {
+    let trackers = get_trackers(&ctx).await;
+    let handle = trackers.lock().await.remove(&command.channel_id);
+
+    let content = match handle {
+        Some(handle) => {
+            // A tracker that is still starting sees its reservation is gone and doesn't start
+            if let Some(handle) = handle {
+                handle.abort();
+            }
+            "Stopped the live tracker"
+        }
+        None => "No live tracker is running in this channel",
+    };
+
+    util::generate_message(ctx, command, content.to_string()).await
+}
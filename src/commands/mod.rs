@@ -1,3 +1,13 @@
 pub mod f1;
+pub mod f1_tools;
+pub mod live;
+pub mod moderation;
 pub mod openai;
+pub mod persona;
+pub mod quota;
+pub mod race_control;
+pub mod registry;
+pub mod summarize;
+pub mod transcribe;
+pub mod usage;
 pub mod util;
@@ -0,0 +1,275 @@
+use crate::commands::registry::SubCommand;
+use crate::commands::util;
+use crate::llm::moderation;
+use crate::store;
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use serenity::builder::CreateApplicationCommandOption;
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use serenity::model::mention::Mentionable;
+use serenity::model::permissions::Permissions;
+use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+
+/// Longest part of flagged text quoted in the mod channel
+const EXCERPT_LENGTH: usize = 500;
+
+/// What happens to flagged prompts and answers
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    #[default]
+    Block,
+    Warn,
+    LogOnly,
+}
+
+impl ModerationAction {
+    fn name(&self) -> &'static str {
+        match self {
+            ModerationAction::Block => "Block",
+            ModerationAction::Warn => "Warn",
+            ModerationAction::LogOnly => "Log only",
+        }
+    }
+}
+
+/// Moderation settings changed with `/ai moderation`
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ModerationSettings {
+    /// Left out until the guild picks one, flagged text is blocked until then
+    #[serde(default)]
+    pub action: Option<ModerationAction>,
+    /// Where flagged prompts and answers are reported
+    pub channel_id: Option<ChannelId>,
+}
+
+impl ModerationSettings {
+    /// The chosen action, or blocking for guilds that haven't picked one
+    fn action(&self) -> ModerationAction {
+        self.action.unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Stage {
+    Prompt,
+    Answer,
+}
+
+/// Outcome of moderating a prompt or answer
+#[derive(PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    /// Flagged, but the guild only wants the user warned
+    Warn,
+    Block,
+}
+
+/// Whether answers have to be held back until they are moderated, only when text is moderated at
+/// all and the guild blocks flagged answers, which it does until it picks another action
+pub async fn hides_progress(ctx: &Context, guild_id: Option<GuildId>) -> bool {
+    let active = moderation::get(ctx).await.is_active();
+    holds_back(active, &settings(ctx, guild_id).await)
+}
+
+fn holds_back(active: bool, settings: &ModerationSettings) -> bool {
+    active && settings.action() == ModerationAction::Block
+}
+
+async fn settings(ctx: &Context, guild_id: Option<GuildId>) -> ModerationSettings {
+    let store = store::get(ctx).await;
+
+    store
+        .read(|data| {
+            guild_id
+                .and_then(|guild_id| data.ai_moderation.get(&guild_id))
+                .cloned()
+                .unwrap_or_default()
+        })
+        .await
+}
+
+/// Runs text through moderation and reports it to the guild's mod channel when flagged. Text is
+/// let through when moderation itself fails, so an outage doesn't take the AI commands down too.
+pub async fn review(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    user_id: UserId,
+    channel_id: ChannelId,
+    stage: Stage,
+    text: &str,
+) -> Verdict {
+    let moderator = moderation::get(ctx).await;
+    let reason = match moderator.check(text).await {
+        Ok(Some(reason)) => reason,
+        Ok(None) => return Verdict::Pass,
+        Err(why) => {
+            println!("Cannot moderate AI text: {}", why);
+            return Verdict::Pass;
+        }
+    };
+
+    let settings = settings(ctx, guild_id).await;
+    let stage = match stage {
+        Stage::Prompt => "prompt",
+        Stage::Answer => "answer",
+    };
+    println!(
+        "Flagged AI {} for user {} in channel {}: {}",
+        stage, user_id, channel_id, reason
+    );
+
+    if let Some(mod_channel) = settings.channel_id {
+        let excerpt: String = text.chars().take(EXCERPT_LENGTH).collect();
+        let content = format!(
+            "Flagged AI {} for {} in {} ({})\n**Action:** {}\n>>> {}",
+            stage,
+            user_id.mention(),
+            channel_id.mention(),
+            reason,
+            settings.action().name(),
+            excerpt
+        );
+        if let Err(why) = mod_channel.say(&ctx.http, content).await {
+            println!("Cannot report flagged AI text: {}", why);
+        }
+    }
+
+    match settings.action() {
+        ModerationAction::Block => Verdict::Block,
+        ModerationAction::Warn => Verdict::Warn,
+        ModerationAction::LogOnly => Verdict::Pass,
+    }
+}
+
+/// `/ai moderation`
+pub struct Moderation;
+
+#[async_trait]
+impl SubCommand for Moderation {
+    fn name(&self) -> &str {
+        "moderation"
+    }
+
+    fn description(&self) -> &str {
+        "Change how flagged AI prompts and answers are handled"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("action")
+                    .description("What happens to flagged prompts and answers")
+                    .kind(CommandOptionType::String)
+                    .add_string_choice("Block", "block")
+                    .add_string_choice("Warn", "warn")
+                    .add_string_choice("Log only", "log_only")
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("channel")
+                    .description("Where flagged prompts and answers are reported")
+                    .kind(CommandOptionType::Channel)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("reset")
+                    .description("Go back to the default settings first")
+                    .kind(CommandOptionType::Boolean)
+            });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        configure(ctx, command).await
+    }
+}
+
+/// Changes what happens to flagged AI prompts and answers and where they are reported
+pub async fn configure(ctx: Context, command: ApplicationCommandInteraction) {
+    let guild_id = match command.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            util::generate_message(
+                ctx,
+                command,
+                "AI moderation can only be changed in a server".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    if !util::has_permission(&command, Permissions::MANAGE_GUILD) {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "You need the Manage Server permission to change AI moderation".to_string(),
+        )
+        .await;
+        return;
+    }
+
+    let action = match util::get_sub_option(&command, "action").and_then(|value| value.as_str()) {
+        Some("block") => Some(ModerationAction::Block),
+        Some("warn") => Some(ModerationAction::Warn),
+        Some("log_only") => Some(ModerationAction::LogOnly),
+        _ => None,
+    };
+    let channel_id = util::get_sub_option(&command, "channel")
+        .and_then(|value| value.as_str())
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(ChannelId);
+    let reset = util::get_sub_option(&command, "reset")
+        .and_then(|value| value.as_bool())
+        .unwrap_or(false);
+
+    let store = store::get(&ctx).await;
+    let settings = store
+        .write(|data| {
+            let settings = data.ai_moderation.entry(guild_id).or_default();
+            if reset {
+                *settings = ModerationSettings::default();
+            }
+            if action.is_some() {
+                settings.action = action;
+            }
+            if channel_id.is_some() {
+                settings.channel_id = channel_id;
+            }
+            settings.clone()
+        })
+        .await;
+
+    let channel = match settings.channel_id {
+        Some(channel_id) => channel_id.mention().to_string(),
+        None => "None".to_string(),
+    };
+    let content = format!(
+        "**Flagged content:** {}\n**Mod channel:** {}",
+        settings.action().name(),
+        channel
+    );
+
+    util::generate_ephemeral_message(ctx, command, content).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_back_answers_until_an_action_is_picked() {
+        let unset: ModerationSettings = serde_json::from_str(r#"{"channel_id": null}"#).unwrap();
+        assert!(holds_back(true, &unset));
+        assert!(!holds_back(false, &unset));
+
+        let log_only = ModerationSettings {
+            action: Some(ModerationAction::LogOnly),
+            channel_id: None,
+        };
+        assert!(!holds_back(true, &log_only));
+    }
+}
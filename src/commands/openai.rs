@@ -1,91 +1,1364 @@
-use crate::commands::util;
-use reqwest::{Client, Error};
-use serde_json::json;
+use crate::commands::moderation::{self, Stage, Verdict};
+use crate::commands::registry::{SlashCommand, SubCommand, SubCommands};
+use crate::commands::util::{self, MESSAGE_LIMIT};
+use crate::commands::{f1_tools, persona, quota, summarize, transcribe, usage};
+use crate::http::{self, HttpClient};
+use crate::llm::{
+    self, ChatMessage, Completion, CompletionOptions, LlmBackend, LlmError, Tool, ToolCall, Usage,
+};
+use crate::store;
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommandOption};
 use serenity::client::Context;
-use serenity::json::Value;
+use serenity::model::application::command::{CommandOptionType, CommandType};
+use serenity::model::channel::{AttachmentType, ChannelType, Message};
+use serenity::model::id::{AttachmentId, ChannelId, GuildId, UserId};
+use serenity::model::mention::Mentionable;
+use serenity::model::permissions::Permissions;
 use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+use serenity::prelude::{Mutex, TypeMapKey};
+use std::collections::HashMap;
 use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const MODEL: &str = "gpt-3.5-turbo-0301";
-const URL: &str = "https://api.openai.com/v1/chat/completions";
-
-async fn create_request(val: Value) -> Result<String, Error> {
-    dotenv::dotenv().expect("Failed to load .env file");
-    let token = env::var("OPENAI_API_KEY").expect("Expected a token in the environment");
-
-    // Create body. Add period at the end of the prompt to help AI determine the end
-    let body = json!({
-        "model": MODEL,
-        "temperature": 0.3,
-        "max_tokens": 2000,
-        "top_p": 1.0,
-        "frequency_penalty": 0.2,
-        "presence_penalty": 0.35,
-        "messages": [
+const TEMPERATURE: f64 = 0.3;
+const MAX_TOKENS: u64 = 2000;
+/// Models that can be chosen unless `LLM_MODELS` lists others
+const DEFAULT_MODELS: [&str; 3] = [MODEL, "gpt-3.5-turbo", "gpt-4"];
+/// Models that accept image attachments unless `LLM_VISION_MODELS` lists others
+const DEFAULT_VISION_MODELS: [&str; 4] = [
+    "gpt-4o",
+    "gpt-4o-mini",
+    "gpt-4-turbo",
+    "gpt-4-vision-preview",
+];
+/// How often a streamed answer is edited into the response while it is generated
+const EDIT_INTERVAL: Duration = Duration::from_millis(1200);
+/// Used by `/ai image` when no size is picked
+const IMAGE_SIZE: &str = "512x512";
+/// Put above answers when the prompt or answer was flagged and the server only warns
+pub const MODERATION_WARNING: &str = "⚠️ *This conversation was flagged by moderation*";
+/// Names of the context menu commands shown on messages
+pub const MESSAGE_ACTIONS: [&str; 3] = ["Explain with AI", "Summarize", "Translate"];
+/// Tool calls answered before the model has to reply with what it has
+const MAX_TOOL_ROUNDS: usize = 4;
+/// Token budget for a conversation's history, leaving the rest of the model's context for the answer
+const CONTEXT_TOKENS: usize = 2000;
+
+/// Overrides a guild has set with `/ai config`, anything left unset uses the defaults
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct AiSettings {
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u64>,
+    pub system_prompt: Option<String>,
+}
+
+impl AiSettings {
+    pub fn resolve(&self) -> CompletionOptions {
+        CompletionOptions {
+            model: self.model.clone().unwrap_or_else(default_model),
+            temperature: self.temperature.unwrap_or(TEMPERATURE),
+            max_tokens: self.max_tokens.unwrap_or(MAX_TOKENS),
+            system_prompt: self.system_prompt.clone(),
+        }
+    }
+}
+
+/// Models users and admins may pick from, read from the comma separated `LLM_MODELS`
+pub fn allowed_models() -> Vec<String> {
+    match env::var("LLM_MODELS") {
+        Ok(models) => models
+            .split(',')
+            .map(|model| model.trim().to_string())
+            .filter(|model| !model.is_empty())
+            .collect(),
+        Err(_) => DEFAULT_MODELS
+            .iter()
+            .map(|model| model.to_string())
+            .collect(),
+    }
+}
+
+/// Models that can look at images, read from the comma separated `LLM_VISION_MODELS`
+fn vision_models() -> Vec<String> {
+    match env::var("LLM_VISION_MODELS") {
+        Ok(models) => models
+            .split(',')
+            .map(|model| model.trim().to_string())
+            .filter(|model| !model.is_empty())
+            .collect(),
+        Err(_) => DEFAULT_VISION_MODELS
+            .iter()
+            .map(|model| model.to_string())
+            .collect(),
+    }
+}
+
+/// The first allowed model is used unless a guild picked another one
+fn default_model() -> String {
+    allowed_models()
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| MODEL.to_string())
+}
+
+/// Looks up the completion settings of the guild a command or message came from, the persona in
+/// use replaces the configured system prompt
+pub async fn completion_options(ctx: &Context, guild_id: Option<GuildId>) -> CompletionOptions {
+    let store = store::get(ctx).await;
+
+    store
+        .read(|data| {
+            let guild_id = guild_id?;
+            let mut options = data
+                .ai_settings
+                .get(&guild_id)
+                .cloned()
+                .unwrap_or_default()
+                .resolve();
+            if let Some(prompt) = data
+                .ai_personas
+                .get(&guild_id)
+                .and_then(|personas| personas.active_prompt())
             {
-                "role": "user",
-                "content": format!("{}.", val)
+                options.system_prompt = Some(prompt.to_string());
             }
-        ]
-    });
-
-    let response = Client::new()
-        .post(URL)
-        .bearer_auth(token)
-        .json(&body)
-        .send()
-        .await;
+            Some(options)
+        })
+        .await
+        .unwrap_or_else(|| AiSettings::default().resolve())
+}
+
+/// Drops the oldest messages until the history fits in the context budget, always keeping the latest
+fn trim_history(history: &mut Vec<ChatMessage>) {
+    let mut total: usize = history.iter().map(ChatMessage::estimated_tokens).sum();
 
-    let response_text = response.unwrap().text().await;
+    while history.len() > 1 && total > CONTEXT_TOKENS {
+        total -= history.remove(0).estimated_tokens();
+    }
+}
+
+/// Tokens a completion probably used, for servers that don't report usage
+pub fn estimate_usage(messages: &[ChatMessage], answer: &str) -> Usage {
+    let prompt: usize = messages.iter().map(ChatMessage::estimated_tokens).sum();
+
+    Usage {
+        prompt_tokens: prompt as u64,
+        completion_tokens: ChatMessage::assistant(answer.to_string()).estimated_tokens() as u64,
+    }
+}
 
-    response_text
+/// Tools the model may call, unless `LLM_TOOLS` is `false` for servers that can't handle them
+fn enabled_tools() -> Vec<Tool> {
+    match env::var("LLM_TOOLS").as_deref() {
+        Ok("false") => Vec::new(),
+        _ => f1_tools::tools(),
+    }
+}
+
+/// Adds the assistant's tool calls and their results to the conversation
+async fn run_tool_calls(
+    http: &HttpClient,
+    messages: &mut Vec<ChatMessage>,
+    text: String,
+    tool_calls: &[ToolCall],
+) {
+    messages.push(ChatMessage::tool_calls(text, tool_calls.to_vec()));
+    for tool_call in tool_calls {
+        let result = f1_tools::call(http, tool_call).await;
+        messages.push(ChatMessage::tool_result(tool_call.id.clone(), result));
+    }
 }
 
-async fn parse_text(response: Result<String, Error>) -> String {
-    match response {
-        Ok(text) => {
-            let v: Value = serde_json::from_str(&text).unwrap();
-            let output = &v["choices"][0]["message"]["content"];
+/// Completes the conversation, calling tools for the model until it answers. The usage returned
+/// covers every round.
+async fn complete_with_tools(
+    backend: &dyn LlmBackend,
+    http: &HttpClient,
+    history: &[ChatMessage],
+    options: &CompletionOptions,
+) -> Result<Completion, LlmError> {
+    let tools = enabled_tools();
+    let mut messages = history.to_vec();
+    let mut total = Usage::default();
 
-            output
-                .to_string()
-                .replace("\\n\\n", " ")
-                .replace("\\n", " ")
-                .replace("\\\"", "")
-                .replace("\"", "")
-                .trim()
-                .to_string()
+    let mut round = 0;
+
+    loop {
+        // After too many rounds the model has to answer with what it has
+        let round_tools: &[Tool] = if round < MAX_TOOL_ROUNDS { &tools } else { &[] };
+        let mut completion = backend.complete(&messages, options, round_tools).await?;
+        let round_usage = completion
+            .usage
+            .unwrap_or_else(|| estimate_usage(&messages, &completion.text));
+        total.prompt_tokens += round_usage.prompt_tokens;
+        total.completion_tokens += round_usage.completion_tokens;
+
+        if completion.tool_calls.is_empty() {
+            completion.usage = Some(total);
+            return Ok(completion);
         }
-        Err(_) => "Did not receive a response from Open Ai :(".to_string(),
+        run_tool_calls(http, &mut messages, completion.text, &completion.tool_calls).await;
+        round += 1;
+    }
+}
+
+/// Counts a completion against the user's quota and records it for `/ai usage`
+pub async fn account(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    user_id: UserId,
+    model: &str,
+    completion_usage: Usage,
+) {
+    quota::record_tokens(ctx, guild_id, user_id, completion_usage.total()).await;
+    usage::record(ctx, guild_id, user_id, model, completion_usage).await;
+}
+
+/// Cuts a partial answer off to fit in a single Discord message, closing any open code block
+fn preview(text: &str) -> String {
+    let limit = MESSAGE_LIMIT - 10;
+    let mut preview: String = text.chars().take(limit).collect();
+
+    if preview.len() < text.len() {
+        preview.push('…');
+    }
+    if preview.matches("```").count() % 2 == 1 {
+        preview.push_str("\n```");
+    }
+
+    preview
+}
+
+/// `/ai`, everything talking to the language model and the settings around it
+pub struct Ai {
+    subcommands: SubCommands,
+}
+
+impl Ai {
+    pub fn new() -> Ai {
+        Ai {
+            subcommands: SubCommands::new(vec![
+                Box::new(Prompt),
+                Box::new(Image),
+                Box::new(Chat),
+                Box::new(summarize::Summarize),
+                Box::new(transcribe::Transcribe),
+                Box::new(Reset),
+                Box::new(Config),
+                Box::new(quota::Limits),
+                Box::new(quota::ResetQuota),
+                Box::new(usage::UsageReport),
+                Box::new(persona::group()),
+                Box::new(moderation::Moderation),
+            ]),
+        }
+    }
+}
+
+impl Default for Ai {
+    fn default() -> Ai {
+        Ai::new()
+    }
+}
+
+#[async_trait]
+impl SlashCommand for Ai {
+    fn name(&self) -> &str {
+        "ai"
+    }
+
+    fn description(&self) -> &str {
+        "Interact with an Open AI model"
+    }
+
+    fn options(&self, command: &mut CreateApplicationCommand) {
+        self.subcommands.register(command);
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        self.subcommands.run(0, ctx, command).await
+    }
+}
+
+/// One of the `MESSAGE_ACTIONS` context menu commands
+pub struct MessageAction(pub &'static str);
+
+#[async_trait]
+impl SlashCommand for MessageAction {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn kind(&self) -> CommandType {
+        CommandType::Message
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        message_action(ctx, command).await
+    }
+}
+
+/// `/ai prompt`
+pub struct Prompt;
+
+#[async_trait]
+impl SubCommand for Prompt {
+    fn name(&self) -> &str {
+        "prompt"
+    }
+
+    fn description(&self) -> &str {
+        "Ask OpenAI a single question"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("text")
+                    .description("The text sent to OpenAI")
+                    .kind(CommandOptionType::String)
+                    .required(true)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("model")
+                    .description("The model used for this prompt")
+                    .kind(CommandOptionType::String);
+                for model in allowed_models() {
+                    sub_option.add_string_choice(&model, &model);
+                }
+                sub_option
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("temperature")
+                    .description("Higher values give more random answers")
+                    .kind(CommandOptionType::Number)
+                    .min_number_value(0.0)
+                    .max_number_value(2.0)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("persona")
+                    .description("A persona made with /ai persona to answer as")
+                    .kind(CommandOptionType::String)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("attachment")
+                    .description("An image for vision capable models to look at")
+                    .kind(CommandOptionType::Attachment)
+            });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        text_prompt(ctx, command).await
     }
 }
 
 pub async fn text_prompt(ctx: Context, command: ApplicationCommandInteraction) {
-    let value = &command
-        .data
-        .options
-        .get(0)
-        .expect("Expected user to select option")
-        .value;
+    let value = util::get_sub_option(&command, "text");
+
+    let mut options = completion_options(&ctx, command.guild_id).await;
+
+    let model = util::get_sub_option(&command, "model")
+        .and_then(|value| value.as_str())
+        .map(|model| model.to_string());
+    if let Some(model) = model {
+        if !allowed_models().contains(&model) {
+            util::generate_ephemeral_message(
+                ctx,
+                command,
+                format!("The model {} is not available", model),
+            )
+            .await;
+            return;
+        }
+        options.model = model;
+    }
+    if let Some(temperature) =
+        util::get_sub_option(&command, "temperature").and_then(|value| value.as_f64())
+    {
+        if !(0.0..=2.0).contains(&temperature) {
+            util::generate_ephemeral_message(
+                ctx,
+                command,
+                "Temperature has to be between 0 and 2".to_string(),
+            )
+            .await;
+            return;
+        }
+        options.temperature = temperature;
+    }
+
+    let persona = util::get_sub_option(&command, "persona")
+        .and_then(|value| value.as_str())
+        .map(|name| name.to_string());
+    if let Some(name) = persona {
+        match persona::find(&ctx, command.guild_id, &name).await {
+            Some(prompt) => options.system_prompt = Some(prompt),
+            None => {
+                util::generate_ephemeral_message(
+                    ctx,
+                    command,
+                    format!("There is no persona called {}", name),
+                )
+                .await;
+                return;
+            }
+        }
+    }
+
+    let attachment = util::get_sub_option(&command, "attachment")
+        .and_then(|value| value.as_str())
+        .and_then(|id| id.parse::<u64>().ok())
+        .and_then(|id| command.data.resolved.attachments.get(&AttachmentId(id)))
+        .cloned();
+    let mut images = Vec::new();
+    if let Some(attachment) = attachment {
+        let is_image = attachment
+            .content_type
+            .as_deref()
+            .is_some_and(|content_type| content_type.starts_with("image/"));
+        if !is_image {
+            util::generate_ephemeral_message(
+                ctx,
+                command,
+                "Only images can be attached to a prompt".to_string(),
+            )
+            .await;
+            return;
+        }
+        if !vision_models().contains(&options.model) {
+            util::generate_ephemeral_message(
+                ctx,
+                command,
+                format!("The model {} cannot look at images", options.model),
+            )
+            .await;
+            return;
+        }
+        images.push(attachment.url);
+    }
 
-    match value.to_owned() {
+    match value.cloned() {
         Some(val) => {
-            // Responses from OpenAI can take more than 3 seconds to be generated.
-            // To avoid timing out the bot, generate an initial message and then edit the message
-            // later when the AI response has been retrieved.
-            util::generate_message(
-                ctx.to_owned(),
-                command.to_owned(),
-                format!("```{}```", "Please wait... Fetching response..."),
+            if let Err(reason) = quota::acquire(&ctx, command.guild_id, command.user.id).await {
+                util::generate_ephemeral_message(ctx, command, reason).await;
+                return;
+            }
+
+            // Responses from OpenAI can take more than 3 seconds to be generated, so the answer
+            // is edited into a deferred response once it starts arriving
+            if !util::defer(&ctx, &command, false).await {
+                quota::refund(&ctx, command.guild_id, command.user.id).await;
+                return;
+            }
+
+            let prompt = val.as_str().unwrap_or_default();
+            let prompt_verdict = moderation::review(
+                &ctx,
+                command.guild_id,
+                command.user.id,
+                command.channel_id,
+                Stage::Prompt,
+                prompt,
             )
             .await;
+            if prompt_verdict == Verdict::Block {
+                quota::refund(&ctx, command.guild_id, command.user.id).await;
+                let text =
+                    "This prompt was blocked by the server's moderation settings".to_string();
+                util::edit_generated_message(ctx, command, text).await;
+                return;
+            }
+            // A blocked answer must never be shown, not even while it is being generated
+            let show_progress = !moderation::hides_progress(&ctx, command.guild_id).await;
 
-            let response = create_request(val).await;
-            let text = parse_text(response).await;
+            let mut messages = vec![ChatMessage::user_with_images(prompt.to_string(), images)];
+            let backend = llm::get(&ctx).await;
+            let http = http::get(&ctx).await;
+            let tools = enabled_tools();
+
+            // Show the answer as it is generated, but edit at most once per interval to stay
+            // clear of Discord's rate limits
+            let mut text = String::new();
+            let mut last_edit = Instant::now();
+            let mut edited_len = 0;
+            let mut completion_usage = Usage::default();
+
+            let mut round = 0;
+
+            loop {
+                // After too many rounds the model has to answer with what it has
+                let round_tools: &[Tool] = if round < MAX_TOOL_ROUNDS { &tools } else { &[] };
+                let mut stream = match backend.stream(&messages, &options, round_tools).await {
+                    Ok(stream) => stream,
+                    Err(why) => {
+                        println!("Cannot reach the AI backend: {}", why);
+                        quota::refund(&ctx, command.guild_id, command.user.id).await;
+                        let text = "Did not receive a response from Open Ai :(".to_string();
+                        util::edit_generated_message(ctx, command, text).await;
+                        return;
+                    }
+                };
+
+                while let Some(piece) = stream.next().await {
+                    match piece {
+                        Ok(piece) => text.push_str(&piece),
+                        Err(why) => {
+                            println!("AI stream ended early: {}", why);
+                            break;
+                        }
+                    }
+
+                    if show_progress
+                        && last_edit.elapsed() >= EDIT_INTERVAL
+                        && text.len() != edited_len
+                    {
+                        util::edit_generated_message(
+                            ctx.to_owned(),
+                            command.to_owned(),
+                            preview(&text),
+                        )
+                        .await;
+                        last_edit = Instant::now();
+                        edited_len = text.len();
+                    }
+                }
+
+                let round_usage = stream
+                    .usage()
+                    .unwrap_or_else(|| estimate_usage(&messages, &text));
+                completion_usage.prompt_tokens += round_usage.prompt_tokens;
+                completion_usage.completion_tokens += round_usage.completion_tokens;
+
+                let tool_calls = stream.tool_calls();
+                if tool_calls.is_empty() {
+                    break;
+                }
+
+                // Anything said before asking for tools is replaced by the answer that follows
+                util::edit_generated_message(
+                    ctx.to_owned(),
+                    command.to_owned(),
+                    "Looking up the latest F1 data...".to_string(),
+                )
+                .await;
+                run_tool_calls(&http, &mut messages, std::mem::take(&mut text), &tool_calls).await;
+                edited_len = 0;
+                round += 1;
+            }
 
-            // Edit the initial message with AI response
-            util::edit_generated_message(ctx, command, format!("```{}```", text)).await
+            account(
+                &ctx,
+                command.guild_id,
+                command.user.id,
+                &options.model,
+                completion_usage,
+            )
+            .await;
+
+            if text.trim().is_empty() {
+                text = "Did not receive a response from Open Ai :(".to_string();
+            } else {
+                let answer_verdict = moderation::review(
+                    &ctx,
+                    command.guild_id,
+                    command.user.id,
+                    command.channel_id,
+                    Stage::Answer,
+                    &text,
+                )
+                .await;
+                text = match answer_verdict {
+                    Verdict::Block => {
+                        "This answer was withheld by the server's moderation settings".to_string()
+                    }
+                    _ if prompt_verdict == Verdict::Warn || answer_verdict == Verdict::Warn => {
+                        format!("{}\n\n{}", MODERATION_WARNING, text.trim())
+                    }
+                    _ => text,
+                };
+            }
+
+            // Edit the initial message with the full AI response, continuing in follow-ups if needed
+            util::edit_generated_long_message(ctx, command, text.trim().to_string()).await
         }
         None => util::generate_message(ctx, command, "Could not pass text to AI".to_string()).await,
     }
 }
+
+/// Explains, summarizes or translates the message a context menu command was used on, replying
+/// only to the user who asked
+pub async fn message_action(ctx: Context, command: ApplicationCommandInteraction) {
+    let message = command
+        .data
+        .target_id
+        .and_then(|target_id| {
+            command
+                .data
+                .resolved
+                .messages
+                .get(&target_id.to_message_id())
+        })
+        .cloned();
+    let message = match message {
+        Some(message) if !message.content.trim().is_empty() => message,
+        _ => {
+            util::generate_ephemeral_message(
+                ctx,
+                command,
+                "This message has no text to work with".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    let instruction = match command.data.name.as_str() {
+        "Summarize" => "Summarize the following Discord message in a few sentences".to_string(),
+        // Translate into the language of the user's Discord client
+        "Translate" => format!(
+            "Translate the following Discord message into the language with the locale code {}, only reply with the translation",
+            command.locale
+        ),
+        _ => "Explain the following Discord message in simple terms".to_string(),
+    };
+    let prompt = format!(
+        "{}:\n\n{}: {}",
+        instruction, message.author.name, message.content
+    );
+
+    if let Err(reason) = quota::acquire(&ctx, command.guild_id, command.user.id).await {
+        util::generate_ephemeral_message(ctx, command, reason).await;
+        return;
+    }
+
+    if !util::defer(&ctx, &command, true).await {
+        quota::refund(&ctx, command.guild_id, command.user.id).await;
+        return;
+    }
+
+    let verdict = moderation::review(
+        &ctx,
+        command.guild_id,
+        command.user.id,
+        command.channel_id,
+        Stage::Prompt,
+        &message.content,
+    )
+    .await;
+    if verdict == Verdict::Block {
+        quota::refund(&ctx, command.guild_id, command.user.id).await;
+        let text = "This message was blocked by the server's moderation settings".to_string();
+        util::edit_generated_message(ctx, command, text).await;
+        return;
+    }
+
+    let options = completion_options(&ctx, command.guild_id).await;
+    let backend = llm::get(&ctx).await;
+    let http = http::get(&ctx).await;
+    let messages = [ChatMessage::user(prompt)];
+    let answer = match complete_with_tools(backend.as_ref(), &http, &messages, &options).await {
+        Ok(completion) => {
+            let completion_usage = completion.usage.unwrap_or_default();
+            account(
+                &ctx,
+                command.guild_id,
+                command.user.id,
+                &options.model,
+                completion_usage,
+            )
+            .await;
+            completion.text
+        }
+        Err(why) => {
+            println!("Cannot reach the AI backend: {}", why);
+            quota::refund(&ctx, command.guild_id, command.user.id).await;
+            let text = "Did not receive a response from Open Ai :(".to_string();
+            util::edit_generated_message(ctx, command, text).await;
+            return;
+        }
+    };
+
+    let answer_verdict = moderation::review(
+        &ctx,
+        command.guild_id,
+        command.user.id,
+        command.channel_id,
+        Stage::Answer,
+        &answer,
+    )
+    .await;
+    let answer = match answer_verdict {
+        Verdict::Block => {
+            "This answer was withheld by the server's moderation settings".to_string()
+        }
+        _ if verdict == Verdict::Warn || answer_verdict == Verdict::Warn => {
+            format!("{}\n\n{}", MODERATION_WARNING, answer)
+        }
+        _ => answer,
+    };
+
+    util::edit_generated_ephemeral_long_message(ctx, command, answer).await
+}
+
+/// `/ai image`
+pub struct Image;
+
+#[async_trait]
+impl SubCommand for Image {
+    fn name(&self) -> &str {
+        "image"
+    }
+
+    fn description(&self) -> &str {
+        "Generate an image from a description"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("prompt")
+                    .description("What the image should show")
+                    .kind(CommandOptionType::String)
+                    .required(true)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("size")
+                    .description("The size of the image, 512x512 when left out")
+                    .kind(CommandOptionType::String)
+                    .add_string_choice("256x256", "256x256")
+                    .add_string_choice("512x512", "512x512")
+                    .add_string_choice("1024x1024", "1024x1024")
+            });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        generate_image(ctx, command).await
+    }
+}
+
+/// Generates an image from a prompt and attaches it to the response
+pub async fn generate_image(ctx: Context, command: ApplicationCommandInteraction) {
+    let prompt = match util::get_sub_option(&command, "prompt").and_then(|value| value.as_str()) {
+        Some(prompt) => prompt.to_string(),
+        None => {
+            util::generate_message(ctx, command, "Could not pass text to AI".to_string()).await;
+            return;
+        }
+    };
+    let size = util::get_sub_option(&command, "size")
+        .and_then(|value| value.as_str())
+        .unwrap_or(IMAGE_SIZE)
+        .to_string();
+
+    if let Err(reason) = quota::acquire_image(&ctx, command.guild_id, command.user.id).await {
+        util::generate_ephemeral_message(ctx, command, reason).await;
+        return;
+    }
+
+    // Images take a while to generate, so show that the bot is thinking until it is done
+    if !util::defer(&ctx, &command, false).await {
+        quota::refund_image(&ctx, command.guild_id, command.user.id).await;
+        return;
+    }
+
+    let verdict = moderation::review(
+        &ctx,
+        command.guild_id,
+        command.user.id,
+        command.channel_id,
+        Stage::Prompt,
+        &prompt,
+    )
+    .await;
+    if verdict == Verdict::Block {
+        quota::refund_image(&ctx, command.guild_id, command.user.id).await;
+        let text = "This prompt was blocked by the server's moderation settings".to_string();
+        util::edit_generated_message(ctx, command, text).await;
+        return;
+    }
+
+    let backend = llm::get(&ctx).await;
+    let image = match backend.generate_image(&prompt, &size).await {
+        Ok(image) => image,
+        Err(why) => {
+            println!("Cannot generate AI image: {}", why);
+            quota::refund_image(&ctx, command.guild_id, command.user.id).await;
+            let text = match why {
+                LlmError::Unsupported(_) => "This AI backend can't generate images",
+                _ => "Could not generate an image :(",
+            }
+            .to_string();
+            util::edit_generated_message(ctx, command, text).await;
+            return;
+        }
+    };
+
+    util::edit_generated_message(
+        ctx.to_owned(),
+        command.to_owned(),
+        format!("**{}** asked for: {}", command.user.name, prompt),
+    )
+    .await;
+
+    let file = AttachmentType::Bytes {
+        data: image.into(),
+        filename: "image.png".to_string(),
+    };
+    if let Err(why) = command
+        .create_followup_message(&ctx.http, |message| message.add_file(file))
+        .await
+    {
+        println!("Cannot send follow-up message: {}", why);
+    }
+}
+
+/// `/ai chat`
+pub struct Chat;
+
+#[async_trait]
+impl SubCommand for Chat {
+    fn name(&self) -> &str {
+        "chat"
+    }
+
+    fn description(&self) -> &str {
+        "Start a conversation with OpenAI in a new thread"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option.create_sub_option(|sub_option| {
+            sub_option
+                .name("text")
+                .description("The first message of the conversation")
+                .kind(CommandOptionType::String)
+        });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        start_chat(ctx, command).await
+    }
+}
+
+/// Opens a thread where every message is answered with the conversation so far as context
+pub async fn start_chat(ctx: Context, command: ApplicationCommandInteraction) {
+    let prompt = util::get_sub_option(&command, "text")
+        .and_then(|value| value.as_str())
+        .map(|text| text.to_string());
+
+    let name = match &prompt {
+        Some(prompt) => prompt.chars().take(90).collect(),
+        None => format!("AI chat with {}", command.user.name),
+    };
+
+    let thread = command
+        .channel_id
+        .create_private_thread(&ctx.http, |thread| {
+            thread.name(name).kind(ChannelType::PublicThread)
+        })
+        .await;
+
+    let thread = match thread {
+        Ok(thread) => thread,
+        Err(why) => {
+            println!("Cannot create AI chat thread: {}", why);
+            util::generate_ephemeral_message(
+                ctx,
+                command,
+                "Could not open a thread here, try again from a text channel".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    let store = store::get(&ctx).await;
+    store
+        .write(|data| data.conversations.insert(thread.id, Vec::new()))
+        .await;
+
+    util::generate_message(
+        ctx.to_owned(),
+        command.to_owned(),
+        format!(
+            "Started a conversation in {}, every message sent there will be answered",
+            thread.id.mention()
+        ),
+    )
+    .await;
+
+    if let Some(prompt) = prompt {
+        if let Err(why) = thread
+            .id
+            .say(&ctx.http, format!("**{}:** {}", command.user.name, prompt))
+            .await
+        {
+            println!("Cannot post AI chat prompt: {}", why);
+        }
+        reply_in_conversation(&ctx, command.guild_id, command.user.id, thread.id, prompt).await;
+    }
+}
+
+/// `/ai reset`
+pub struct Reset;
+
+#[async_trait]
+impl SubCommand for Reset {
+    fn name(&self) -> &str {
+        "reset"
+    }
+
+    fn description(&self) -> &str {
+        "Forget the conversation so far in this thread"
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        reset_chat(ctx, command).await
+    }
+}
+
+/// Clears the history of the conversation in the current thread
+pub async fn reset_chat(ctx: Context, command: ApplicationCommandInteraction) {
+    let store = store::get(&ctx).await;
+    let reset = store
+        .write(
+            |data| match data.conversations.get_mut(&command.channel_id) {
+                Some(history) => {
+                    history.clear();
+                    true
+                }
+                None => false,
+            },
+        )
+        .await;
+
+    if reset {
+        util::generate_message(ctx, command, "Conversation history cleared".to_string()).await
+    } else {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "This command only works inside an AI chat thread".to_string(),
+        )
+        .await
+    }
+}
+
+/// Locks of the AI chat threads, held while a turn is answered so the turns of a thread are
+/// answered and stored one after the other
+pub struct ConversationLocks;
+
+impl TypeMapKey for ConversationLocks {
+    type Value = Arc<Mutex<HashMap<ChannelId, Arc<Mutex<()>>>>>;
+}
+
+async fn get_conversation_locks(ctx: &Context) -> Arc<Mutex<HashMap<ChannelId, Arc<Mutex<()>>>>> {
+    ctx.data
+        .read()
+        .await
+        .get::<ConversationLocks>()
+        .expect("Expected ConversationLocks in TypeMap")
+        .clone()
+}
+
+/// Forgets the conversation of a thread that was deleted or archived
+pub async fn end_conversation(ctx: &Context, channel_id: ChannelId) {
+    get_conversation_locks(ctx)
+        .await
+        .lock()
+        .await
+        .remove(&channel_id);
+
+    let store = store::get(ctx).await;
+    let in_conversation = store
+        .read(|data| data.conversations.contains_key(&channel_id))
+        .await;
+    if in_conversation {
+        store
+            .write(|data| data.conversations.remove(&channel_id))
+            .await;
+    }
+}
+
+/// Answers a message sent in an AI chat thread, ignoring every other message
+pub async fn chat_message(ctx: Context, msg: Message) {
+    if msg.author.bot || msg.content.is_empty() {
+        return;
+    }
+
+    let store = store::get(&ctx).await;
+    let in_conversation = store
+        .read(|data| data.conversations.contains_key(&msg.channel_id))
+        .await;
+
+    if in_conversation {
+        reply_in_conversation(
+            &ctx,
+            msg.guild_id,
+            msg.author.id,
+            msg.channel_id,
+            msg.content,
+        )
+        .await;
+    }
+}
+
+/// Adds the user's message to the stored history, asks the AI and stores its answer as well
+async fn reply_in_conversation(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    user_id: UserId,
+    channel_id: ChannelId,
+    content: String,
+) {
+    let lock = get_conversation_locks(ctx)
+        .await
+        .lock()
+        .await
+        .entry(channel_id)
+        .or_default()
+        .clone();
+    let _turn = lock.lock().await;
+
+    if let Err(reason) = quota::acquire(ctx, guild_id, user_id).await {
+        if let Err(why) = channel_id.say(&ctx.http, reason).await {
+            println!("Cannot reply in AI chat: {}", why);
+        }
+        return;
+    }
+
+    let prompt_verdict =
+        moderation::review(ctx, guild_id, user_id, channel_id, Stage::Prompt, &content).await;
+    if prompt_verdict == Verdict::Block {
+        quota::refund(ctx, guild_id, user_id).await;
+        if let Err(why) = channel_id
+            .say(
+                &ctx.http,
+                "This message was blocked by the server's moderation settings",
+            )
+            .await
+        {
+            println!("Cannot reply in AI chat: {}", why);
+        }
+        return;
+    }
+
+    let options = completion_options(ctx, guild_id).await;
+    let store = store::get(ctx).await;
+    // The conversation may have ended while waiting for the previous turn
+    let history = store
+        .write(|data| {
+            let history = data.conversations.get_mut(&channel_id)?;
+            history.push(ChatMessage::user(content));
+            trim_history(history);
+            Some(history.clone())
+        })
+        .await;
+    let history = match history {
+        Some(history) => history,
+        None => {
+            quota::refund(ctx, guild_id, user_id).await;
+            return;
+        }
+    };
+
+    let _ = channel_id.broadcast_typing(&ctx.http).await;
+
+    let backend = llm::get(ctx).await;
+    let http = http::get(ctx).await;
+    let answer = match complete_with_tools(backend.as_ref(), &http, &history, &options).await {
+        Ok(completion) => {
+            let completion_usage = completion.usage.unwrap_or_default();
+            account(ctx, guild_id, user_id, &options.model, completion_usage).await;
+            completion.text
+        }
+        Err(why) => {
+            println!("Cannot reach the AI backend: {}", why);
+            quota::refund(ctx, guild_id, user_id).await;
+            if let Err(why) = channel_id
+                .say(&ctx.http, "Did not receive a response from Open Ai :(")
+                .await
+            {
+                println!("Cannot reply in AI chat: {}", why);
+            }
+            return;
+        }
+    };
+
+    let answer_verdict =
+        moderation::review(ctx, guild_id, user_id, channel_id, Stage::Answer, &answer).await;
+    if answer_verdict == Verdict::Block {
+        if let Err(why) = channel_id
+            .say(
+                &ctx.http,
+                "This answer was withheld by the server's moderation settings",
+            )
+            .await
+        {
+            println!("Cannot reply in AI chat: {}", why);
+        }
+        return;
+    }
+
+    store
+        .write(|data| {
+            if let Some(history) = data.conversations.get_mut(&channel_id) {
+                history.push(ChatMessage::assistant(answer.clone()));
+                trim_history(history);
+            }
+        })
+        .await;
+
+    let answer = match (prompt_verdict, answer_verdict) {
+        (Verdict::Warn, _) | (_, Verdict::Warn) => format!("{}\n\n{}", MODERATION_WARNING, answer),
+        _ => answer,
+    };
+    util::send_long_message(ctx, channel_id, answer).await
+}
+
+/// `/ai config`
+pub struct Config;
+
+#[async_trait]
+impl SubCommand for Config {
+    fn name(&self) -> &str {
+        "config"
+    }
+
+    fn description(&self) -> &str {
+        "Change the AI settings for this server"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("model")
+                    .description("The model used by default")
+                    .kind(CommandOptionType::String);
+                for model in allowed_models() {
+                    sub_option.add_string_choice(&model, &model);
+                }
+                sub_option
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("temperature")
+                    .description("Higher values give more random answers")
+                    .kind(CommandOptionType::Number)
+                    .min_number_value(0.0)
+                    .max_number_value(2.0)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("max_tokens")
+                    .description("The longest an answer can be")
+                    .kind(CommandOptionType::Integer)
+                    .min_int_value(1)
+                    .max_int_value(4000)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("system_prompt")
+                    .description("Instructions given to the AI ahead of every prompt, - to clear")
+                    .kind(CommandOptionType::String)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("reset")
+                    .description("Go back to the default settings first")
+                    .kind(CommandOptionType::Boolean)
+            });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        configure(ctx, command).await
+    }
+}
+
+/// Changes the model, temperature, max tokens and system prompt used in this server
+pub async fn configure(ctx: Context, command: ApplicationCommandInteraction) {
+    let guild_id = match command.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            util::generate_message(
+                ctx,
+                command,
+                "AI settings can only be changed in a server".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    if !util::has_permission(&command, Permissions::MANAGE_GUILD) {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "You need the Manage Server permission to change AI settings".to_string(),
+        )
+        .await;
+        return;
+    }
+
+    let model = util::get_sub_option(&command, "model")
+        .and_then(|value| value.as_str())
+        .map(|model| model.to_string());
+    if let Some(model) = &model {
+        if !allowed_models().contains(model) {
+            util::generate_ephemeral_message(
+                ctx,
+                command,
+                format!("The model {} is not available", model),
+            )
+            .await;
+            return;
+        }
+    }
+
+    let temperature =
+        util::get_sub_option(&command, "temperature").and_then(|value| value.as_f64());
+    let max_tokens = util::get_sub_option(&command, "max_tokens").and_then(|value| value.as_u64());
+    let system_prompt = util::get_sub_option(&command, "system_prompt")
+        .and_then(|value| value.as_str())
+        .map(|prompt| prompt.to_string());
+    let reset = util::get_sub_option(&command, "reset")
+        .and_then(|value| value.as_bool())
+        .unwrap_or(false);
+
+    let store = store::get(&ctx).await;
+    let options = store
+        .write(|data| {
+            let settings = data.ai_settings.entry(guild_id).or_default();
+            if reset {
+                *settings = AiSettings::default();
+            }
+            if model.is_some() {
+                settings.model = model;
+            }
+            if let Some(temperature) = temperature {
+                settings.temperature = Some(temperature);
+            }
+            if let Some(max_tokens) = max_tokens {
+                settings.max_tokens = Some(max_tokens);
+            }
+            // An empty prompt can't be sent, so a single dash clears it
+            match system_prompt.as_deref() {
+                Some("-") => settings.system_prompt = None,
+                Some(prompt) => settings.system_prompt = Some(prompt.to_string()),
+                None => {}
+            }
+            settings.resolve()
+        })
+        .await;
+
+    let content = format!(
+        "**Model:** {}\n**Temperature:** {}\n**Max tokens:** {}\n**System prompt:** {}",
+        options.model,
+        options.temperature,
+        options.max_tokens,
+        options.system_prompt.as_deref().unwrap_or("None")
+    );
+
+    util::generate_ephemeral_message(ctx, command, content).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::mock::{MockBackend, MockReply};
+    use crate::llm::{CompletionStream, FunctionCall};
+    use serenity::async_trait;
+    use serenity::prelude::Mutex;
+
+    /// Keeps the conversation of every round so tests can look at what the model was sent
+    struct Recorder {
+        inner: MockBackend,
+        rounds: Mutex<Vec<Vec<ChatMessage>>>,
+    }
+
+    #[async_trait]
+    impl LlmBackend for Recorder {
+        async fn complete(
+            &self,
+            messages: &[ChatMessage],
+            options: &CompletionOptions,
+            tools: &[Tool],
+        ) -> Result<Completion, LlmError> {
+            self.rounds.lock().await.push(messages.to_vec());
+            self.inner.complete(messages, options, tools).await
+        }
+
+        async fn stream(
+            &self,
+            messages: &[ChatMessage],
+            options: &CompletionOptions,
+            tools: &[Tool],
+        ) -> Result<Box<dyn CompletionStream>, LlmError> {
+            self.rounds.lock().await.push(messages.to_vec());
+            self.inner.stream(messages, options, tools).await
+        }
+    }
+
+    fn options() -> CompletionOptions {
+        CompletionOptions {
+            model: "mock".to_string(),
+            temperature: 0.0,
+            max_tokens: 100,
+            system_prompt: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_after_tool_calls() {
+        // Unknown tools are answered with an error without going out to the network
+        let tool_call = ToolCall {
+            id: "call_1".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: "pit_stops".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        let backend = Recorder {
+            inner: MockBackend::new(vec![
+                MockReply::ToolCalls(vec![tool_call]),
+                MockReply::Text("Verstappen leads".to_string()),
+            ]),
+            rounds: Mutex::new(Vec::new()),
+        };
+        let history = vec![ChatMessage::user("Who leads?".to_string())];
+
+        let completion = complete_with_tools(&backend, &HttpClient::new(), &history, &options())
+            .await
+            .unwrap();
+        assert_eq!(completion.text, "Verstappen leads");
+
+        let rounds = backend.rounds.lock().await;
+        assert_eq!(rounds.len(), 2);
+        let second = &rounds[1];
+        assert_eq!(second.len(), 3);
+        assert_eq!(second[1].role, "assistant");
+        assert_eq!(second[1].tool_calls[0].id, "call_1");
+        assert_eq!(second[2].role, "tool");
+        assert_eq!(second[2].tool_call_id.as_deref(), Some("call_1"));
+        assert!(second[2].content.contains("Unknown tool pit_stops"));
+
+        // The mock counts words: "Who leads?" is sent twice, the tool result once
+        let usage = completion.usage.unwrap();
+        let tool_words = second[2].content.split_whitespace().count() as u64;
+        assert_eq!(usage.prompt_tokens, 2 + 2 + tool_words);
+        assert_eq!(usage.completion_tokens, 2);
+    }
+}
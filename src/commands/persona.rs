@@ -0,0 +1,365 @@
+use crate::commands::registry::{Group, SubCommand, SubCommands};
+use crate::commands::util;
+use crate::store;
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use serenity::builder::CreateApplicationCommandOption;
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::id::GuildId;
+use serenity::model::permissions::Permissions;
+use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+use std::collections::BTreeMap;
+
+/// Keeps `/ai persona list` short enough to read
+const MAX_PERSONAS: usize = 25;
+const MAX_NAME_LENGTH: usize = 32;
+/// Longest part of a system prompt shown by `/ai persona list`
+const EXCERPT_LENGTH: usize = 100;
+
+/// Named system prompts a guild has made with `/ai persona create`
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Personas {
+    /// Used by every AI command unless a prompt picks another persona
+    pub active: Option<String>,
+    /// System prompts keyed by their lower case name
+    pub prompts: BTreeMap<String, String>,
+}
+
+impl Personas {
+    /// The system prompt of the persona in use, if any
+    pub fn active_prompt(&self) -> Option<&String> {
+        self.prompts.get(self.active.as_ref()?)
+    }
+}
+
+/// Names are matched case insensitively
+fn get_name(command: &ApplicationCommandInteraction) -> Option<String> {
+    util::get_group_option(command, "name")
+        .and_then(|value| value.as_str())
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+}
+
+/// The guild whose personas may be changed by the user, or why they can't be
+fn managed_guild(command: &ApplicationCommandInteraction) -> Result<GuildId, String> {
+    let guild_id = command
+        .guild_id
+        .ok_or_else(|| "AI personas can only be changed in a server".to_string())?;
+
+    if !util::has_permission(command, Permissions::MANAGE_GUILD) {
+        return Err("You need the Manage Server permission to change AI personas".to_string());
+    }
+    Ok(guild_id)
+}
+
+/// Looks up the system prompt of one of the guild's personas
+pub async fn find(ctx: &Context, guild_id: Option<GuildId>, name: &str) -> Option<String> {
+    let name = name.trim().to_lowercase();
+    let store = store::get(ctx).await;
+
+    store
+        .read(|data| {
+            data.ai_personas
+                .get(&guild_id?)?
+                .prompts
+                .get(&name)
+                .cloned()
+        })
+        .await
+}
+
+/// `/ai persona create`
+pub struct Create;
+
+#[async_trait]
+impl SubCommand for Create {
+    fn name(&self) -> &str {
+        "create"
+    }
+
+    fn description(&self) -> &str {
+        "Create a persona or change its system prompt"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option
+            .create_sub_option(|name| {
+                name.name("name")
+                    .description("What the persona is called")
+                    .kind(CommandOptionType::String)
+                    .required(true)
+            })
+            .create_sub_option(|prompt| {
+                prompt
+                    .name("system_prompt")
+                    .description("How the AI should behave as this persona")
+                    .kind(CommandOptionType::String)
+                    .required(true)
+            });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        create(ctx, command).await
+    }
+}
+
+/// `/ai persona`
+pub fn group() -> Group {
+    Group {
+        name: "persona",
+        description: "Manage named system prompts the AI can answer as",
+        subcommands: SubCommands::new(vec![
+            Box::new(Create),
+            Box::new(List),
+            Box::new(Use),
+            Box::new(Delete),
+        ]),
+    }
+}
+
+/// Adds a persona, or changes the system prompt of an existing one
+pub async fn create(ctx: Context, command: ApplicationCommandInteraction) {
+    let guild_id = match managed_guild(&command) {
+        Ok(guild_id) => guild_id,
+        Err(reason) => {
+            util::generate_ephemeral_message(ctx, command, reason).await;
+            return;
+        }
+    };
+    let name = get_name(&command);
+    let prompt = util::get_group_option(&command, "system_prompt")
+        .and_then(|value| value.as_str())
+        .map(|prompt| prompt.trim().to_string())
+        .filter(|prompt| !prompt.is_empty());
+    let (name, prompt) = match (name, prompt) {
+        (Some(name), Some(prompt)) => (name, prompt),
+        _ => {
+            util::generate_ephemeral_message(
+                ctx,
+                command,
+                "A persona needs a name and a system prompt".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+    if name.chars().count() > MAX_NAME_LENGTH {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            format!(
+                "Persona names can be at most {} characters",
+                MAX_NAME_LENGTH
+            ),
+        )
+        .await;
+        return;
+    }
+
+    let store = store::get(&ctx).await;
+    let result = store
+        .write(|data| {
+            let personas = data.ai_personas.entry(guild_id).or_default();
+            let exists = personas.prompts.contains_key(&name);
+            if !exists && personas.prompts.len() >= MAX_PERSONAS {
+                return Err(format!(
+                    "This server already has {} personas, delete one first",
+                    MAX_PERSONAS
+                ));
+            }
+            personas.prompts.insert(name.clone(), prompt);
+            Ok(exists)
+        })
+        .await;
+
+    let content = match result {
+        Ok(true) => format!("Updated the persona **{}**", name),
+        Ok(false) => format!(
+            "Created the persona **{}**, pick it with `/ai persona use` or the persona option of `/ai prompt`",
+            name
+        ),
+        Err(reason) => reason,
+    };
+    util::generate_ephemeral_message(ctx, command, content).await
+}
+
+/// `/ai persona list`
+pub struct List;
+
+#[async_trait]
+impl SubCommand for List {
+    fn name(&self) -> &str {
+        "list"
+    }
+
+    fn description(&self) -> &str {
+        "Show the personas of this server"
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        list(ctx, command).await
+    }
+}
+
+/// Shows the personas of this server and which one is in use
+pub async fn list(ctx: Context, command: ApplicationCommandInteraction) {
+    let store = store::get(&ctx).await;
+    let personas = store
+        .read(|data| {
+            command
+                .guild_id
+                .and_then(|guild_id| data.ai_personas.get(&guild_id))
+                .cloned()
+                .unwrap_or_default()
+        })
+        .await;
+
+    if personas.prompts.is_empty() {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "There are no personas yet, create one with `/ai persona create`".to_string(),
+        )
+        .await;
+        return;
+    }
+
+    let mut content = "**AI personas**\n".to_string();
+    for (name, prompt) in &personas.prompts {
+        let marker = if personas.active.as_ref() == Some(name) {
+            " (in use)"
+        } else {
+            ""
+        };
+        let mut excerpt: String = prompt.chars().take(EXCERPT_LENGTH).collect();
+        if excerpt.len() < prompt.len() {
+            excerpt.push('…');
+        }
+        content.push_str(&format!("**{}**{} — {}\n", name, marker, excerpt));
+    }
+
+    // A full list of long names and prompts doesn't fit in a single message
+    util::generate_ephemeral_long_message(ctx, command, content).await
+}
+
+/// `/ai persona use`
+pub struct Use;
+
+#[async_trait]
+impl SubCommand for Use {
+    fn name(&self) -> &str {
+        "use"
+    }
+
+    fn description(&self) -> &str {
+        "Answer as a persona by default, or as none when left out"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option.create_sub_option(|name| {
+            name.name("name")
+                .description("The persona to use")
+                .kind(CommandOptionType::String)
+        });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        use_persona(ctx, command).await
+    }
+}
+
+/// Makes a persona the default for this server, or goes back to no persona when no name is given
+pub async fn use_persona(ctx: Context, command: ApplicationCommandInteraction) {
+    let guild_id = match managed_guild(&command) {
+        Ok(guild_id) => guild_id,
+        Err(reason) => {
+            util::generate_ephemeral_message(ctx, command, reason).await;
+            return;
+        }
+    };
+    let name = get_name(&command);
+
+    let store = store::get(&ctx).await;
+    let found = store
+        .write(|data| {
+            let personas = data.ai_personas.entry(guild_id).or_default();
+            match &name {
+                Some(name) if !personas.prompts.contains_key(name) => false,
+                _ => {
+                    personas.active = name.clone();
+                    true
+                }
+            }
+        })
+        .await;
+
+    let content = match (name, found) {
+        (Some(name), true) => format!("The AI now answers as **{}**", name),
+        (Some(name), false) => format!("There is no persona called **{}**", name),
+        (None, _) => "The AI no longer uses a persona".to_string(),
+    };
+    util::generate_ephemeral_message(ctx, command, content).await
+}
+
+/// `/ai persona delete`
+pub struct Delete;
+
+#[async_trait]
+impl SubCommand for Delete {
+    fn name(&self) -> &str {
+        "delete"
+    }
+
+    fn description(&self) -> &str {
+        "Delete a persona"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option.create_sub_option(|name| {
+            name.name("name")
+                .description("The persona to delete")
+                .kind(CommandOptionType::String)
+                .required(true)
+        });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        delete(ctx, command).await
+    }
+}
+
+/// Removes a persona, the AI stops using it if it was in use
+pub async fn delete(ctx: Context, command: ApplicationCommandInteraction) {
+    let guild_id = match managed_guild(&command) {
+        Ok(guild_id) => guild_id,
+        Err(reason) => {
+            util::generate_ephemeral_message(ctx, command, reason).await;
+            return;
+        }
+    };
+    let name = match get_name(&command) {
+        Some(name) => name,
+        None => {
+            util::generate_ephemeral_message(ctx, command, "Name the persona".to_string()).await;
+            return;
+        }
+    };
+
+    let store = store::get(&ctx).await;
+    let removed = store
+        .write(|data| {
+            let personas = data.ai_personas.entry(guild_id).or_default();
+            if personas.active.as_ref() == Some(&name) {
+                personas.active = None;
+            }
+            personas.prompts.remove(&name).is_some()
+        })
+        .await;
+
+    let content = match removed {
+        true => format!("Deleted the persona **{}**", name),
+        false => format!("There is no persona called **{}**", name),
+    };
+    util::generate_ephemeral_message(ctx, command, content).await
+}
@@ -0,0 +1,467 @@
+use crate::commands::registry::SubCommand;
+use crate::commands::util;
+use crate::store;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use serenity::builder::CreateApplicationCommandOption;
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::id::{GuildId, UserId};
+use serenity::model::mention::Mentionable;
+use serenity::model::permissions::Permissions;
+use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+use std::collections::HashMap;
+
+const COOLDOWN_SECS: u64 = 10;
+const USER_REQUESTS: u64 = 50;
+const USER_TOKENS: u64 = 20000;
+const GUILD_REQUESTS: u64 = 500;
+const GUILD_TOKENS: u64 = 200000;
+const USER_IMAGES: u64 = 5;
+/// Direct messages have no guild, their usage is counted under this id instead. Only the per-user
+/// limits apply to them, so one user can't use up everyone else's direct messages.
+const DIRECT_MESSAGES: GuildId = GuildId(0);
+
+/// Limits a guild has set with `/ai limits`, anything left unset uses the defaults and 0 means
+/// unlimited
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct QuotaLimits {
+    pub cooldown_secs: Option<u64>,
+    pub user_requests: Option<u64>,
+    pub user_tokens: Option<u64>,
+    pub guild_requests: Option<u64>,
+    pub guild_tokens: Option<u64>,
+    pub user_images: Option<u64>,
+}
+
+impl QuotaLimits {
+    fn cooldown_secs(&self) -> u64 {
+        self.cooldown_secs.unwrap_or(COOLDOWN_SECS)
+    }
+
+    fn user_requests(&self) -> u64 {
+        self.user_requests.unwrap_or(USER_REQUESTS)
+    }
+
+    fn user_tokens(&self) -> u64 {
+        self.user_tokens.unwrap_or(USER_TOKENS)
+    }
+
+    fn guild_requests(&self) -> u64 {
+        self.guild_requests.unwrap_or(GUILD_REQUESTS)
+    }
+
+    fn guild_tokens(&self) -> u64 {
+        self.guild_tokens.unwrap_or(GUILD_TOKENS)
+    }
+
+    fn user_images(&self) -> u64 {
+        self.user_images.unwrap_or(USER_IMAGES)
+    }
+}
+
+/// What a guild and its members have used today
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    pub day: Option<NaiveDate>,
+    pub requests: u64,
+    pub tokens: u64,
+    pub users: HashMap<UserId, UserUsage>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct UserUsage {
+    pub requests: u64,
+    pub tokens: u64,
+    pub last_request: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub images: u64,
+}
+
+impl QuotaUsage {
+    /// Quotas are daily, so usage from a previous day is thrown away
+    fn roll_over(&mut self, today: NaiveDate) {
+        if self.day != Some(today) {
+            *self = QuotaUsage {
+                day: Some(today),
+                ..QuotaUsage::default()
+            };
+        }
+    }
+}
+
+/// A limit of 0 turns the limit off
+fn exceeded(used: u64, limit: u64) -> bool {
+    limit > 0 && used >= limit
+}
+
+/// Counts a request against the quotas, or explains which limit stops it from being sent
+pub async fn acquire(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    user_id: UserId,
+) -> Result<(), String> {
+    let shared = guild_id.is_some();
+    let guild_id = guild_id.unwrap_or(DIRECT_MESSAGES);
+    let now = Utc::now();
+    let store = store::get(ctx).await;
+
+    store
+        .write(|data| {
+            let limits = data.ai_limits.get(&guild_id).cloned().unwrap_or_default();
+            let usage = data.ai_usage.entry(guild_id).or_default();
+            usage.roll_over(now.date_naive());
+
+            if shared && exceeded(usage.requests, limits.guild_requests()) {
+                return Err(
+                    "This server has used all of its AI requests for today, they reset at midnight UTC"
+                        .to_string(),
+                );
+            }
+            if shared && exceeded(usage.tokens, limits.guild_tokens()) {
+                return Err(
+                    "This server has used all of its AI tokens for today, they reset at midnight UTC"
+                        .to_string(),
+                );
+            }
+
+            let user = usage.users.entry(user_id).or_default();
+            if let Some(last_request) = user.last_request {
+                let ready = last_request + chrono::Duration::seconds(limits.cooldown_secs() as i64);
+                if now < ready {
+                    return Err(format!(
+                        "Slow down! You can ask again in {}s",
+                        (ready - now).num_seconds().max(1)
+                    ));
+                }
+            }
+            if exceeded(user.requests, limits.user_requests()) {
+                return Err(format!(
+                    "You have used all {} of your AI requests for today, they reset at midnight UTC",
+                    limits.user_requests()
+                ));
+            }
+            if exceeded(user.tokens, limits.user_tokens()) {
+                return Err(
+                    "You have used all of your AI tokens for today, they reset at midnight UTC"
+                        .to_string(),
+                );
+            }
+
+            user.requests += 1;
+            user.last_request = Some(now);
+            usage.requests += 1;
+            Ok(())
+        })
+        .await
+}
+
+/// Gives back a request counted by `acquire` when it never reached the AI, e.g. because the
+/// backend failed or moderation blocked the prompt. The cooldown still applies.
+pub async fn refund(ctx: &Context, guild_id: Option<GuildId>, user_id: UserId) {
+    let guild_id = guild_id.unwrap_or(DIRECT_MESSAGES);
+    let store = store::get(ctx).await;
+
+    store
+        .write(|data| {
+            let usage = data.ai_usage.entry(guild_id).or_default();
+            // Nothing to give back when the quota reset in the meantime
+            usage.roll_over(Utc::now().date_naive());
+            usage.requests = usage.requests.saturating_sub(1);
+            let user = usage.users.entry(user_id).or_default();
+            user.requests = user.requests.saturating_sub(1);
+        })
+        .await
+}
+
+/// Counts an image against the user's daily image quota, or explains why it can't be generated
+pub async fn acquire_image(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    user_id: UserId,
+) -> Result<(), String> {
+    let guild_id = guild_id.unwrap_or(DIRECT_MESSAGES);
+    let store = store::get(ctx).await;
+
+    store
+        .write(|data| {
+            let limits = data.ai_limits.get(&guild_id).cloned().unwrap_or_default();
+            let usage = data.ai_usage.entry(guild_id).or_default();
+            usage.roll_over(Utc::now().date_naive());
+
+            let user = usage.users.entry(user_id).or_default();
+            if exceeded(user.images, limits.user_images()) {
+                return Err(format!(
+                    "You have generated all {} of your AI images for today, they reset at midnight UTC",
+                    limits.user_images()
+                ));
+            }
+
+            user.images += 1;
+            Ok(())
+        })
+        .await
+}
+
+/// Gives back an image counted by `acquire_image` when none was generated after all
+pub async fn refund_image(ctx: &Context, guild_id: Option<GuildId>, user_id: UserId) {
+    let guild_id = guild_id.unwrap_or(DIRECT_MESSAGES);
+    let store = store::get(ctx).await;
+
+    store
+        .write(|data| {
+            let usage = data.ai_usage.entry(guild_id).or_default();
+            // Nothing to give back when the quota reset in the meantime
+            usage.roll_over(Utc::now().date_naive());
+            let user = usage.users.entry(user_id).or_default();
+            user.images = user.images.saturating_sub(1);
+        })
+        .await
+}
+
+/// Adds the tokens a completion used to the user's and guild's usage
+pub async fn record_tokens(ctx: &Context, guild_id: Option<GuildId>, user_id: UserId, tokens: u64) {
+    let guild_id = guild_id.unwrap_or(DIRECT_MESSAGES);
+    let store = store::get(ctx).await;
+
+    store
+        .write(|data| {
+            let usage = data.ai_usage.entry(guild_id).or_default();
+            usage.roll_over(Utc::now().date_naive());
+            usage.tokens += tokens;
+            usage.users.entry(user_id).or_default().tokens += tokens;
+        })
+        .await
+}
+
+fn format_limit(limit: u64) -> String {
+    match limit {
+        0 => "Unlimited".to_string(),
+        limit => limit.to_string(),
+    }
+}
+
+/// `/ai limits`
+pub struct Limits;
+
+#[async_trait]
+impl SubCommand for Limits {
+    fn name(&self) -> &str {
+        "limits"
+    }
+
+    fn description(&self) -> &str {
+        "Change the AI cooldown and daily quotas for this server, 0 for unlimited"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("cooldown")
+                    .description("Seconds a user has to wait between requests")
+                    .kind(CommandOptionType::Integer)
+                    .min_int_value(0)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("user_requests")
+                    .description("Requests each user can make per day")
+                    .kind(CommandOptionType::Integer)
+                    .min_int_value(0)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("user_tokens")
+                    .description("Tokens each user can use per day")
+                    .kind(CommandOptionType::Integer)
+                    .min_int_value(0)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("guild_requests")
+                    .description("Requests the whole server can make per day")
+                    .kind(CommandOptionType::Integer)
+                    .min_int_value(0)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("guild_tokens")
+                    .description("Tokens the whole server can use per day")
+                    .kind(CommandOptionType::Integer)
+                    .min_int_value(0)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("user_images")
+                    .description("Images each user can generate per day")
+                    .kind(CommandOptionType::Integer)
+                    .min_int_value(0)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("reset")
+                    .description("Go back to the default limits first")
+                    .kind(CommandOptionType::Boolean)
+            });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        limits(ctx, command).await
+    }
+}
+
+/// Changes the cooldown and daily quotas used in this server
+pub async fn limits(ctx: Context, command: ApplicationCommandInteraction) {
+    let guild_id = match command.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            util::generate_message(
+                ctx,
+                command,
+                "AI limits can only be changed in a server".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    if !util::has_permission(&command, Permissions::MANAGE_GUILD) {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "You need the Manage Server permission to change AI limits".to_string(),
+        )
+        .await;
+        return;
+    }
+
+    let get_limit = |name| util::get_sub_option(&command, name).and_then(|value| value.as_u64());
+    let cooldown_secs = get_limit("cooldown");
+    let user_requests = get_limit("user_requests");
+    let user_tokens = get_limit("user_tokens");
+    let guild_requests = get_limit("guild_requests");
+    let guild_tokens = get_limit("guild_tokens");
+    let user_images = get_limit("user_images");
+    let reset = util::get_sub_option(&command, "reset")
+        .and_then(|value| value.as_bool())
+        .unwrap_or(false);
+
+    let store = store::get(&ctx).await;
+    let (limits, usage) = store
+        .write(|data| {
+            let limits = data.ai_limits.entry(guild_id).or_default();
+            if reset {
+                *limits = QuotaLimits::default();
+            }
+            limits.cooldown_secs = cooldown_secs.or(limits.cooldown_secs);
+            limits.user_requests = user_requests.or(limits.user_requests);
+            limits.user_tokens = user_tokens.or(limits.user_tokens);
+            limits.guild_requests = guild_requests.or(limits.guild_requests);
+            limits.guild_tokens = guild_tokens.or(limits.guild_tokens);
+            limits.user_images = user_images.or(limits.user_images);
+
+            let mut usage = data.ai_usage.get(&guild_id).cloned().unwrap_or_default();
+            usage.roll_over(Utc::now().date_naive());
+            (limits.clone(), usage)
+        })
+        .await;
+
+    let content = format!(
+        "**Cooldown:** {}s\n**Requests per user:** {}\n**Tokens per user:** {}\n**Requests per server:** {}\n**Tokens per server:** {}\n**Images per user:** {}\n\nToday this server used {} requests and {} tokens",
+        limits.cooldown_secs(),
+        format_limit(limits.user_requests()),
+        format_limit(limits.user_tokens()),
+        format_limit(limits.guild_requests()),
+        format_limit(limits.guild_tokens()),
+        format_limit(limits.user_images()),
+        usage.requests,
+        usage.tokens
+    );
+
+    util::generate_ephemeral_message(ctx, command, content).await
+}
+
+/// `/ai reset_quota`
+pub struct ResetQuota;
+
+#[async_trait]
+impl SubCommand for ResetQuota {
+    fn name(&self) -> &str {
+        "reset_quota"
+    }
+
+    fn description(&self) -> &str {
+        "Give back today's AI quota"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option.create_sub_option(|sub_option| {
+            sub_option
+                .name("user")
+                .description("Only reset this user, everyone when left out")
+                .kind(CommandOptionType::User)
+        });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        reset_quota(ctx, command).await
+    }
+}
+
+/// Gives a user, or everyone in the server, their daily quota back
+pub async fn reset_quota(ctx: Context, command: ApplicationCommandInteraction) {
+    let guild_id = match command.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            util::generate_message(
+                ctx,
+                command,
+                "AI quotas can only be reset in a server".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    if !util::has_permission(&command, Permissions::MANAGE_GUILD) {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "You need the Manage Server permission to reset AI quotas".to_string(),
+        )
+        .await;
+        return;
+    }
+
+    let user_id = util::get_sub_option(&command, "user")
+        .and_then(|value| value.as_str())
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(UserId);
+
+    let store = store::get(&ctx).await;
+    store
+        .write(|data| {
+            if let Some(usage) = data.ai_usage.get_mut(&guild_id) {
+                match user_id {
+                    // The user's share of the server's totals is given back as well
+                    Some(user_id) => {
+                        if let Some(user) = usage.users.remove(&user_id) {
+                            usage.requests = usage.requests.saturating_sub(user.requests);
+                            usage.tokens = usage.tokens.saturating_sub(user.tokens);
+                        }
+                    }
+                    None => *usage = QuotaUsage::default(),
+                }
+            }
+        })
+        .await;
+
+    let content = match user_id {
+        Some(user_id) => format!("Reset today's AI quota for {}", user_id.mention()),
+        None => "Reset today's AI quota for everyone in this server".to_string(),
+    };
+
+    util::generate_ephemeral_message(ctx, command, content).await
+}
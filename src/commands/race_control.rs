@@ -0,0 +1,203 @@
+use crate::commands::registry::{Group, SubCommand, SubCommands};
+use crate::commands::util;
+use crate::openf1::RaceControlCategory;
+use crate::store::{self, RaceControlSubscription};
+use serenity::async_trait;
+use serenity::builder::CreateApplicationCommandOption;
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::channel::ChannelType;
+use serenity::model::id::ChannelId;
+use serenity::model::mention::Mentionable;
+use serenity::model::permissions::Permissions;
+use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+
+/// `/f1 race_control subscribe`
+pub struct Subscribe;
+
+#[async_trait]
+impl SubCommand for Subscribe {
+    fn name(&self) -> &str {
+        "subscribe"
+    }
+
+    fn description(&self) -> &str {
+        "Post race control messages to a channel"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option.create_sub_option(|channel| {
+            channel
+                .name("channel")
+                .description("Where to post, defaults to this channel")
+                .kind(CommandOptionType::Channel)
+                .channel_types(&[ChannelType::Text])
+        });
+        for (name, description) in [
+            ("flags", "Track flags (default: on)"),
+            ("safety_car", "Safety car periods (default: on)"),
+            ("penalties", "Penalties handed out (default: on)"),
+            (
+                "investigations",
+                "Incidents under investigation (default: on)",
+            ),
+            (
+                "track_limits",
+                "Lap times deleted for track limits (default: on)",
+            ),
+            (
+                "other",
+                "Everything else, e.g. blue flags and DRS (default: off)",
+            ),
+        ] {
+            option.create_sub_option(|category| {
+                category
+                    .name(name)
+                    .description(description)
+                    .kind(CommandOptionType::Boolean)
+            });
+        }
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        subscribe(ctx, command).await
+    }
+}
+
+/// `/f1 race_control`
+pub fn group() -> Group {
+    Group {
+        name: "race_control",
+        description: "Post race control messages and penalties during sessions",
+        subcommands: SubCommands::new(vec![Box::new(Subscribe), Box::new(Unsubscribe)]),
+    }
+}
+
+/// Starts posting race control messages to a channel, filtered by the chosen categories
+pub async fn subscribe(ctx: Context, command: ApplicationCommandInteraction) {
+    let guild_id = match command.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            util::generate_message(
+                ctx,
+                command,
+                "The race control feed can only be set up in a server".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    if !util::has_permission(&command, Permissions::MANAGE_CHANNELS) {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "You need the Manage Channels permission to set up the race control feed".to_string(),
+        )
+        .await;
+        return;
+    }
+
+    let channel_id = util::get_group_option(&command, "channel")
+        .and_then(|value| value.as_str())
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(ChannelId)
+        .unwrap_or(command.channel_id);
+
+    // Everything but the noisy catch-all category is on unless turned off
+    let categories: Vec<RaceControlCategory> = RaceControlCategory::ALL
+        .into_iter()
+        .filter(|category| {
+            util::get_group_option(&command, category.option_name())
+                .and_then(|value| value.as_bool())
+                .unwrap_or(*category != RaceControlCategory::Other)
+        })
+        .collect();
+
+    let names: Vec<&str> = categories
+        .iter()
+        .map(|category| category.option_name())
+        .collect();
+
+    let store = store::get(&ctx).await;
+    store
+        .write(|data| {
+            data.race_control.insert(
+                guild_id,
+                RaceControlSubscription {
+                    channel_id,
+                    categories,
+                    last_seen: None,
+                    seen: Vec::new(),
+                },
+            )
+        })
+        .await;
+
+    util::generate_message(
+        ctx,
+        command,
+        format!(
+            "Race control messages will be posted in {} ({})",
+            channel_id.mention(),
+            names.join(", ")
+        ),
+    )
+    .await
+}
+
+/// `/f1 race_control unsubscribe`
+pub struct Unsubscribe;
+
+#[async_trait]
+impl SubCommand for Unsubscribe {
+    fn name(&self) -> &str {
+        "unsubscribe"
+    }
+
+    fn description(&self) -> &str {
+        "Stop posting race control messages"
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        unsubscribe(ctx, command).await
+    }
+}
+
+/// Stops the race control feed for the server
+pub async fn unsubscribe(ctx: Context, command: ApplicationCommandInteraction) {
+    let guild_id = match command.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            util::generate_message(
+                ctx,
+                command,
+                "The race control feed can only be set up in a server".to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+
+    if !util::has_permission(&command, Permissions::MANAGE_CHANNELS) {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "You need the Manage Channels permission to turn off the race control feed".to_string(),
+        )
+        .await;
+        return;
+    }
+
+    let store = store::get(&ctx).await;
+    let removed = store
+        .write(|data| data.race_control.remove(&guild_id))
+        .await;
+
+    let content = match removed {
+        Some(_) => "Race control messages will no longer be posted",
+        None => "The race control feed is not set up in this server",
+    };
+
+    util::generate_message(ctx, command, content.to_string()).await
+}
@@ -0,0 +1,545 @@
+use crate::commands::{f1, openai, util};
+use serde_json::{json, Value};
+use serenity::async_trait;
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommandOption};
+use serenity::client::Context;
+use serenity::http::Http;
+use serenity::model::application::command::{Command, CommandOptionType, CommandType};
+use serenity::model::id::{CommandId, GuildId};
+use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+use std::env;
+use std::fmt;
+
+/// A command users can run, defining both how it is registered with Discord and what it does
+#[async_trait]
+pub trait SlashCommand: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Only shown for slash commands, context menu commands have no description
+    fn description(&self) -> &str {
+        ""
+    }
+
+    fn kind(&self) -> CommandType {
+        CommandType::ChatInput
+    }
+
+    /// Adds the options, subcommands and subcommand groups of the command
+    fn options(&self, _command: &mut CreateApplicationCommand) {}
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction);
+}
+
+/// A subcommand or subcommand group, defined next to its handler and composed into a command
+/// with `SubCommands`
+#[async_trait]
+pub trait SubCommand: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn description(&self) -> &str;
+
+    fn kind(&self) -> CommandOptionType {
+        CommandOptionType::SubCommand
+    }
+
+    /// Adds the options of a subcommand, or the subcommands of a group
+    fn options(&self, _option: &mut CreateApplicationCommandOption) {}
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction);
+}
+
+/// The subcommands of a command or group, registering them and running the one the user picked
+pub struct SubCommands(Vec<Box<dyn SubCommand>>);
+
+impl SubCommands {
+    pub fn new(subcommands: Vec<Box<dyn SubCommand>>) -> SubCommands {
+        SubCommands(subcommands)
+    }
+
+    fn build<'a>(
+        subcommand: &dyn SubCommand,
+        option: &'a mut CreateApplicationCommandOption,
+    ) -> &'a mut CreateApplicationCommandOption {
+        option
+            .name(subcommand.name())
+            .description(subcommand.description())
+            .kind(subcommand.kind());
+        subcommand.options(option);
+        option
+    }
+
+    /// Adds the subcommands as options of a command
+    pub fn register(&self, command: &mut CreateApplicationCommand) {
+        for subcommand in &self.0 {
+            command.create_option(|option| SubCommands::build(subcommand.as_ref(), option));
+        }
+    }
+
+    /// Adds the subcommands to a group
+    pub fn register_in(&self, group: &mut CreateApplicationCommandOption) {
+        for subcommand in &self.0 {
+            group.create_sub_option(|option| SubCommands::build(subcommand.as_ref(), option));
+        }
+    }
+
+    /// Runs the subcommand named at `depth` of what the user picked, 0 for a command's own
+    /// subcommands and 1 for those of a group
+    pub async fn run(&self, depth: usize, ctx: Context, command: ApplicationCommandInteraction) {
+        let path = subcommand(&command);
+        let name = path.split(' ').nth(depth).unwrap_or_default();
+
+        match self.0.iter().find(|subcommand| subcommand.name() == name) {
+            Some(subcommand) => subcommand.run(ctx, command).await,
+            None => invalid_option(ctx, command).await,
+        }
+    }
+}
+
+/// A subcommand group such as `/ai persona`
+pub struct Group {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub subcommands: SubCommands,
+}
+
+#[async_trait]
+impl SubCommand for Group {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    fn kind(&self) -> CommandOptionType {
+        CommandOptionType::SubCommandGroup
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        self.subcommands.register_in(option);
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        self.subcommands.run(1, ctx, command).await
+    }
+}
+
+/// Where commands are registered
+#[derive(Clone, Copy)]
+enum Scope {
+    /// Every server the bot is in, changes can take up to an hour to show up
+    Global,
+    /// A single server, changes show up right away which is handy during development
+    Guild(GuildId),
+}
+
+impl Scope {
+    /// `GUILD_ID` holds one or more comma separated guild ids, global when it is empty
+    fn from_env() -> Vec<Scope> {
+        let scopes: Vec<Scope> = env::var("GUILD_ID")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .filter_map(|id| match id.parse::<u64>() {
+                Ok(id) => Some(Scope::Guild(GuildId(id))),
+                Err(_) => {
+                    println!("Ignoring invalid guild id in GUILD_ID: {}", id);
+                    None
+                }
+            })
+            .collect();
+
+        match scopes.is_empty() {
+            true => vec![Scope::Global],
+            false => scopes,
+        }
+    }
+
+    async fn commands(self, http: &Http) -> serenity::Result<Vec<Command>> {
+        match self {
+            Scope::Global => Command::get_global_application_commands(http).await,
+            Scope::Guild(guild_id) => guild_id.get_application_commands(http).await,
+        }
+    }
+
+    async fn create(self, http: &Http, slash_command: &dyn SlashCommand) -> serenity::Result<()> {
+        match self {
+            Scope::Global => {
+                Command::create_global_application_command(http, |command| {
+                    Registry::build(slash_command, command)
+                })
+                .await?
+            }
+            Scope::Guild(guild_id) => {
+                guild_id
+                    .create_application_command(http, |command| {
+                        Registry::build(slash_command, command)
+                    })
+                    .await?
+            }
+        };
+        Ok(())
+    }
+
+    async fn edit(
+        self,
+        http: &Http,
+        command_id: CommandId,
+        slash_command: &dyn SlashCommand,
+    ) -> serenity::Result<()> {
+        match self {
+            Scope::Global => {
+                Command::edit_global_application_command(http, command_id, |command| {
+                    Registry::build(slash_command, command)
+                })
+                .await?
+            }
+            Scope::Guild(guild_id) => {
+                guild_id
+                    .edit_application_command(http, command_id, |command| {
+                        Registry::build(slash_command, command)
+                    })
+                    .await?
+            }
+        };
+        Ok(())
+    }
+
+    async fn delete(self, http: &Http, command_id: CommandId) -> serenity::Result<()> {
+        match self {
+            Scope::Global => Command::delete_global_application_command(http, command_id).await,
+            Scope::Guild(guild_id) => guild_id.delete_application_command(http, command_id).await,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Global => write!(f, "globally"),
+            Scope::Guild(guild_id) => write!(f, "in {}", guild_id),
+        }
+    }
+}
+
+/// What a sync changed in one scope
+#[derive(Default)]
+struct SyncSummary {
+    created: usize,
+    updated: usize,
+    deleted: usize,
+    unchanged: usize,
+    failed: usize,
+}
+
+impl fmt::Display for SyncSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} created, {} updated, {} deleted, {} unchanged, {} failed",
+            self.created, self.updated, self.deleted, self.unchanged, self.failed
+        )
+    }
+}
+
+/// Every command the bot has, registered on startup and used to dispatch interactions
+pub struct Registry {
+    commands: Vec<Box<dyn SlashCommand>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        let mut commands: Vec<Box<dyn SlashCommand>> = vec![
+            Box::new(util::Ping),
+            Box::new(f1::F1::new()),
+            Box::new(openai::Ai::new()),
+        ];
+        for name in openai::MESSAGE_ACTIONS {
+            commands.push(Box::new(openai::MessageAction(name)));
+        }
+
+        Registry { commands }
+    }
+
+    /// Fills in the registration of a single command
+    fn build<'a>(
+        slash_command: &dyn SlashCommand,
+        command: &'a mut CreateApplicationCommand,
+    ) -> &'a mut CreateApplicationCommand {
+        command
+            .name(slash_command.name())
+            .kind(slash_command.kind());
+        if slash_command.kind() == CommandType::ChatInput {
+            command.description(slash_command.description());
+        }
+        slash_command.options(command);
+        command
+    }
+
+    /// The registration of a single command as it is sent to Discord
+    fn definition(slash_command: &dyn SlashCommand) -> Value {
+        let mut command = CreateApplicationCommand::default();
+        Registry::build(slash_command, &mut command);
+
+        Value::Object(
+            command
+                .0
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// Brings the commands registered with Discord in line with the registry, only creating,
+    /// editing and deleting the ones that changed. Returns false when any of it failed.
+    pub async fn sync(&self, http: &Http) -> bool {
+        let mut ok = true;
+        for scope in Scope::from_env() {
+            let summary = self.sync_scope(http, scope).await;
+            println!("Synced commands {}: {}", scope, summary);
+            ok &= summary.failed == 0;
+        }
+        ok
+    }
+
+    async fn sync_scope(&self, http: &Http, scope: Scope) -> SyncSummary {
+        let mut summary = SyncSummary::default();
+        let mut registered = match scope.commands(http).await {
+            Ok(registered) => registered,
+            Err(why) => {
+                println!("Cannot fetch the commands registered {}: {}", scope, why);
+                summary.failed += 1;
+                return summary;
+            }
+        };
+
+        for slash_command in &self.commands {
+            let slash_command = slash_command.as_ref();
+            let found = registered.iter().position(|command| {
+                command.name == slash_command.name() && command.kind == slash_command.kind()
+            });
+
+            match found.map(|index| registered.swap_remove(index)) {
+                None => match scope.create(http, slash_command).await {
+                    Ok(()) => summary.created += 1,
+                    Err(why) => {
+                        println!("Cannot create {} {}: {}", slash_command.name(), scope, why);
+                        summary.failed += 1;
+                    }
+                },
+                Some(command) if same(&Registry::definition(slash_command), &command) => {
+                    summary.unchanged += 1
+                }
+                Some(command) => match scope.edit(http, command.id, slash_command).await {
+                    Ok(()) => summary.updated += 1,
+                    Err(why) => {
+                        println!("Cannot update {} {}: {}", slash_command.name(), scope, why);
+                        summary.failed += 1;
+                    }
+                },
+            }
+        }
+
+        // Whatever is left was removed from the registry
+        for command in registered {
+            match scope.delete(http, command.id).await {
+                Ok(()) => summary.deleted += 1,
+                Err(why) => {
+                    println!("Cannot delete {} {}: {}", command.name, scope, why);
+                    summary.failed += 1;
+                }
+            }
+        }
+
+        summary
+    }
+
+    /// Runs the command the interaction was for
+    pub async fn dispatch(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        let found = self.commands.iter().find(|slash_command| {
+            slash_command.name() == command.data.name && slash_command.kind() == command.data.kind
+        });
+
+        match found {
+            Some(slash_command) => slash_command.run(ctx, command).await,
+            None => util::generate_message(ctx, command, "Not implemented :(".to_string()).await,
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+/// Whether a registered command still matches its definition
+fn same(definition: &Value, command: &Command) -> bool {
+    match serde_json::to_value(command) {
+        Ok(command) => normalize(definition) == normalize(&command),
+        Err(_) => false,
+    }
+}
+
+/// The parts of a command or option the bot sets, with Discord's defaults filled in so a
+/// definition and what Discord sends back can be compared
+fn normalize(v: &Value) -> Value {
+    let options: Vec<Value> = v["options"]
+        .as_array()
+        .map(|options| options.iter().map(normalize).collect())
+        .unwrap_or_default();
+    let choices: Vec<Value> = v["choices"]
+        .as_array()
+        .map(|choices| {
+            choices
+                .iter()
+                .map(|choice| json!({ "name": choice["name"], "value": choice["value"] }))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    json!({
+        "type": v["type"].as_u64().unwrap_or(1),
+        "name": v["name"],
+        "description": v["description"].as_str().unwrap_or_default(),
+        "required": v["required"].as_bool().unwrap_or_default(),
+        "choices": choices,
+        "channel_types": v["channel_types"].as_array().cloned().unwrap_or_default(),
+        // Discord sends whole numbers back without a fraction
+        "min_value": v["min_value"].as_f64(),
+        "max_value": v["max_value"].as_f64(),
+        "options": options,
+    })
+}
+
+/// The subcommand group and subcommand the user picked as they are typed, e.g. `persona create`
+pub fn subcommand(command: &ApplicationCommandInteraction) -> String {
+    let mut names = Vec::new();
+    let mut options = &command.data.options;
+
+    while let Some(option) = options.first().filter(|option| {
+        matches!(
+            option.kind,
+            CommandOptionType::SubCommand | CommandOptionType::SubCommandGroup
+        )
+    }) {
+        names.push(option.name.as_str());
+        options = &option.options;
+    }
+    names.join(" ")
+}
+
+/// Answers subcommands the bot doesn't know, such as ones removed since they were registered
+pub async fn invalid_option(ctx: Context, command: ApplicationCommandInteraction) {
+    util::generate_message(ctx, command, "Invalid option".to_string()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `/ai` as Discord sends it back when listing the registered commands. The model choices
+    /// come from `LLM_MODELS`, so they are filled in the same way the definition fills them.
+    fn ai_fixture() -> Value {
+        let mut fixture = serde_json::from_str(include_str!("fixtures/ai.json")).unwrap();
+        let choices: Vec<Value> = openai::allowed_models()
+            .into_iter()
+            .map(|model| json!({ "name": model, "value": model }))
+            .collect();
+        for path in [["prompt", "model"], ["config", "model"]] {
+            ai_option(&mut fixture, &path)["choices"] = json!(choices);
+        }
+        fixture
+    }
+
+    fn command(value: Value) -> Command {
+        serde_json::from_value(value).expect("Fixture is not a valid command")
+    }
+
+    fn ai_option<'a>(fixture: &'a mut Value, path: &[&str]) -> &'a mut Value {
+        path.iter().fold(fixture, |option, name| {
+            option["options"]
+                .as_array_mut()
+                .unwrap()
+                .iter_mut()
+                .find(|option| option["name"] == *name)
+                .unwrap()
+        })
+    }
+
+    #[test]
+    fn ai_matches_what_discord_sends_back() {
+        let definition = Registry::definition(&openai::Ai::new());
+
+        // Discord leaves out `required: false` and sends whole floats such as 0.0 as integers
+        let mut fixture = ai_fixture();
+        let temperature = ai_option(&mut fixture, &["prompt", "temperature"]);
+        assert_eq!(temperature["min_value"], json!(0));
+        assert!(temperature.get("required").is_none());
+
+        assert!(same(&definition, &command(fixture)));
+    }
+
+    #[test]
+    fn explicit_required_false_matches_omitted() {
+        let mut fixture = ai_fixture();
+        ai_option(&mut fixture, &["prompt", "temperature"])["required"] = json!(false);
+
+        assert!(same(
+            &Registry::definition(&openai::Ai::new()),
+            &command(fixture)
+        ));
+    }
+
+    #[test]
+    fn detects_changed_ranges() {
+        let definition = Registry::definition(&openai::Ai::new());
+
+        let mut fixture = ai_fixture();
+        ai_option(&mut fixture, &["prompt", "temperature"])["min_value"] = json!(0.5);
+        assert!(!same(&definition, &command(fixture)));
+
+        let mut fixture = ai_fixture();
+        ai_option(&mut fixture, &["config", "max_tokens"])["max_value"] = json!(4001);
+        assert!(!same(&definition, &command(fixture)));
+    }
+
+    #[test]
+    fn detects_changed_options() {
+        let definition = Registry::definition(&openai::Ai::new());
+
+        let mut fixture = ai_fixture();
+        ai_option(&mut fixture, &["persona", "delete"])["description"] = json!("Remove a persona");
+        assert!(!same(&definition, &command(fixture)));
+
+        let mut fixture = ai_fixture();
+        ai_option(&mut fixture, &["persona", "delete", "name"])
+            .as_object_mut()
+            .unwrap()
+            .remove("required");
+        assert!(!same(&definition, &command(fixture)));
+
+        let mut fixture = ai_fixture();
+        fixture["options"].as_array_mut().unwrap().pop();
+        assert!(!same(&definition, &command(fixture)));
+    }
+
+    #[test]
+    fn message_action_matches_empty_description() {
+        let fixture = json!({
+            "id": "1060253117216034890",
+            "application_id": "1060252479815950416",
+            "version": "1161478937532846125",
+            "default_member_permissions": null,
+            "type": 3,
+            "name": "Summarize",
+            "description": "",
+            "dm_permission": true,
+        });
+        let action = openai::MessageAction("Summarize");
+
+        assert!(same(&Registry::definition(&action), &command(fixture)));
+    }
+}
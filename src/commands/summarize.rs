@@ -0,0 +1,377 @@
+use crate::commands::moderation::{self, Stage, Verdict};
+use crate::commands::openai;
+use crate::commands::registry::SubCommand;
+use crate::commands::{quota, util};
+use crate::llm::{self, ChatMessage, CompletionOptions, LlmBackend, LlmError, Usage};
+use chrono::{Duration, Utc};
+use serenity::async_trait;
+use serenity::builder::CreateApplicationCommandOption;
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::channel::Message;
+use serenity::model::id::{ChannelId, GuildId, MessageId};
+use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+
+const DEFAULT_COUNT: u64 = 100;
+const MAX_COUNT: u64 = 2000;
+/// Furthest back `since` can reach, the message count runs out long before this in busy channels
+const MAX_SINCE_DAYS: i64 = 30;
+/// Discord hands out channel history a page of at most 100 messages at a time
+const PAGE_SIZE: u64 = 100;
+/// Estimated tokens of conversation sent in a single request, leaving room for the summary
+const CHUNK_TOKENS: usize = 3000;
+/// Longest a single message is quoted, so one wall of text can't crowd out the rest
+const MAX_LINE_LENGTH: usize = 500;
+
+const MAP_INSTRUCTION: &str = "Summarize this part of a Discord conversation in a few bullet points. Every message starts with a marker such as [#12], put the marker of the messages a point is based on after it.";
+const REDUCE_INSTRUCTION: &str = "Combine these summaries of consecutive parts of a Discord conversation into one concise digest of at most ten bullet points, keeping the [#12] style markers of the most important messages.";
+
+/// Parses how far back to look, e.g. `30m`, `2h` or `1d`, up to `MAX_SINCE_DAYS`
+fn parse_since(since: &str) -> Option<Duration> {
+    let since = since.trim();
+    let (amount, to_duration): (&str, fn(i64) -> Option<Duration>) =
+        if let Some(amount) = since.strip_suffix('m') {
+            (amount, Duration::try_minutes)
+        } else if let Some(amount) = since.strip_suffix('h') {
+            (amount, Duration::try_hours)
+        } else {
+            (since.strip_suffix('d')?, Duration::try_days)
+        };
+    let amount: i64 = amount.trim().parse().ok()?;
+    if amount <= 0 {
+        return None;
+    }
+
+    to_duration(amount).filter(|since| *since <= Duration::days(MAX_SINCE_DAYS))
+}
+
+/// Fetches up to `count` of the channel's latest messages sent after `since`, oldest first
+async fn fetch_history(
+    ctx: &Context,
+    channel_id: ChannelId,
+    count: u64,
+    since: Option<Duration>,
+) -> serenity::Result<Vec<Message>> {
+    let oldest = since.and_then(|since| Utc::now().checked_sub_signed(since));
+    let mut messages: Vec<Message> = Vec::new();
+    let mut before: Option<MessageId> = None;
+
+    while (messages.len() as u64) < count {
+        let limit = PAGE_SIZE.min(count - messages.len() as u64);
+        let page = channel_id
+            .messages(&ctx.http, |retriever| match before {
+                Some(before) => retriever.before(before).limit(limit),
+                None => retriever.limit(limit),
+            })
+            .await?;
+        let full_page = page.len() as u64 == limit;
+        before = page.last().map(|message| message.id);
+
+        let mut reached_oldest = false;
+        for message in page {
+            if oldest.is_some_and(|oldest| message.timestamp.unix_timestamp() < oldest.timestamp())
+            {
+                reached_oldest = true;
+                break;
+            }
+            messages.push(message);
+        }
+
+        if reached_oldest || !full_page {
+            break;
+        }
+    }
+
+    messages.reverse();
+    Ok(messages)
+}
+
+/// Numbers the messages so the model can point at them, leaving out bots and empty messages
+fn format_lines(messages: &[Message]) -> Vec<(MessageId, String)> {
+    messages
+        .iter()
+        .filter(|message| !message.author.bot && !message.content.trim().is_empty())
+        .enumerate()
+        .map(|(i, message)| {
+            let content: String = message.content.chars().take(MAX_LINE_LENGTH).collect();
+            (
+                message.id,
+                format!("[#{}] {}: {}", i + 1, message.author.name, content),
+            )
+        })
+        .collect()
+}
+
+/// Groups lines into chunks that each fit in a single request
+fn chunk_lines(lines: &[(MessageId, String)]) -> Vec<String> {
+    let mut chunks: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut tokens = 0;
+
+    for (_, line) in lines {
+        let line_tokens = line.chars().count() / 4 + 4;
+        if tokens + line_tokens > CHUNK_TOKENS && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+            tokens = 0;
+        }
+        current.push_str(line);
+        current.push('\n');
+        tokens += line_tokens;
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+/// Replaces the `[#12]` markers the model kept with jump links to the messages
+fn link_markers(
+    digest: &str,
+    lines: &[(MessageId, String)],
+    channel_id: ChannelId,
+    guild_id: Option<GuildId>,
+) -> String {
+    let mut digest = digest.to_string();
+
+    // Highest numbers first so `[#1]` never matches the start of `[#12]`
+    for (i, (message_id, _)) in lines.iter().enumerate().rev() {
+        let marker = format!("[#{}]", i + 1);
+        if digest.contains(&marker) {
+            let link = format!("[↗]({})", message_id.link(channel_id, guild_id));
+            digest = digest.replace(&marker, &link);
+        }
+    }
+
+    digest
+}
+
+/// Runs a single summarization request, adding its tokens to `total`
+async fn summarize(
+    backend: &dyn LlmBackend,
+    options: &CompletionOptions,
+    instruction: &str,
+    text: String,
+    total: &mut Usage,
+) -> Result<String, LlmError> {
+    let messages = [ChatMessage::user(format!("{}\n\n{}", instruction, text))];
+    let completion = backend.complete(&messages, options, &[]).await?;
+    let usage = completion
+        .usage
+        .unwrap_or_else(|| openai::estimate_usage(&messages, &completion.text));
+    total.prompt_tokens += usage.prompt_tokens;
+    total.completion_tokens += usage.completion_tokens;
+
+    Ok(completion.text)
+}
+
+/// Summarizes each chunk of the conversation, then combines the summaries into one digest
+async fn map_reduce(
+    backend: &dyn LlmBackend,
+    options: &CompletionOptions,
+    chunks: Vec<String>,
+    total: &mut Usage,
+) -> Result<String, LlmError> {
+    let mut summaries: Vec<String> = Vec::new();
+    for chunk in chunks {
+        summaries.push(summarize(backend, options, MAP_INSTRUCTION, chunk, total).await?);
+    }
+
+    // A single chunk is summarized directly into the digest
+    if summaries.len() == 1 {
+        return Ok(summaries.remove(0));
+    }
+
+    // Combine in groups so the summaries themselves never outgrow a request
+    while summaries.len() > 1 {
+        let lines: Vec<(MessageId, String)> = summaries
+            .drain(..)
+            .map(|summary| (MessageId(0), summary))
+            .collect();
+        let mut combined = Vec::new();
+        for group in chunk_lines(&lines) {
+            combined.push(summarize(backend, options, REDUCE_INSTRUCTION, group, total).await?);
+        }
+        // Stop once a pass can't shrink the summaries any further
+        if combined.len() == lines.len() {
+            return Ok(combined.join("\n"));
+        }
+        summaries = combined;
+    }
+
+    Ok(summaries.remove(0))
+}
+
+/// `/ai summarize`
+pub struct Summarize;
+
+#[async_trait]
+impl SubCommand for Summarize {
+    fn name(&self) -> &str {
+        "summarize"
+    }
+
+    fn description(&self) -> &str {
+        "Summarize the recent messages in this channel"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("count")
+                    .description("How many messages to read, 100 when left out")
+                    .kind(CommandOptionType::Integer)
+                    .min_int_value(1)
+                    .max_int_value(2000)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("since")
+                    .description("Only read messages from the last 30m, 2h, 1d...")
+                    .kind(CommandOptionType::String)
+            });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        channel_summary(ctx, command).await
+    }
+}
+
+/// Posts a digest of the channel's recent messages with links to the most important ones
+pub async fn channel_summary(ctx: Context, command: ApplicationCommandInteraction) {
+    let count = util::get_sub_option(&command, "count")
+        .and_then(|value| value.as_u64())
+        .unwrap_or(DEFAULT_COUNT)
+        .min(MAX_COUNT);
+    let since_option = util::get_sub_option(&command, "since")
+        .and_then(|value| value.as_str())
+        .map(|since| since.to_string());
+    let since = match &since_option {
+        Some(since_option) => match parse_since(since_option) {
+            Some(since) => Some(since),
+            None => {
+                util::generate_ephemeral_message(
+                    ctx,
+                    command,
+                    format!(
+                        "Use a time such as 30m, 2h or 1d for since, at most {}d",
+                        MAX_SINCE_DAYS
+                    ),
+                )
+                .await;
+                return;
+            }
+        },
+        None => None,
+    };
+
+    if let Err(reason) = quota::acquire(&ctx, command.guild_id, command.user.id).await {
+        util::generate_ephemeral_message(ctx, command, reason).await;
+        return;
+    }
+
+    // Reading and summarizing a busy channel takes a while
+    if !util::defer(&ctx, &command, false).await {
+        quota::refund(&ctx, command.guild_id, command.user.id).await;
+        return;
+    }
+
+    let history = match fetch_history(&ctx, command.channel_id, count, since).await {
+        Ok(history) => history,
+        Err(why) => {
+            println!("Cannot fetch channel history: {}", why);
+            quota::refund(&ctx, command.guild_id, command.user.id).await;
+            let text = "Could not read this channel's messages".to_string();
+            util::edit_generated_message(ctx, command, text).await;
+            return;
+        }
+    };
+
+    let lines = format_lines(&history);
+    if lines.is_empty() {
+        quota::refund(&ctx, command.guild_id, command.user.id).await;
+        let text = "There are no messages to summarize".to_string();
+        util::edit_generated_message(ctx, command, text).await;
+        return;
+    }
+
+    let options = openai::completion_options(&ctx, command.guild_id).await;
+    let backend = llm::get(&ctx).await;
+    let mut total = Usage::default();
+    let result = map_reduce(backend.as_ref(), &options, chunk_lines(&lines), &mut total).await;
+    openai::account(
+        &ctx,
+        command.guild_id,
+        command.user.id,
+        &options.model,
+        total,
+    )
+    .await;
+
+    let digest = match result {
+        Ok(digest) => digest,
+        Err(why) => {
+            println!("Cannot reach the AI backend: {}", why);
+            quota::refund(&ctx, command.guild_id, command.user.id).await;
+            let text = "Did not receive a response from Open Ai :(".to_string();
+            util::edit_generated_message(ctx, command, text).await;
+            return;
+        }
+    };
+
+    let verdict = moderation::review(
+        &ctx,
+        command.guild_id,
+        command.user.id,
+        command.channel_id,
+        Stage::Answer,
+        &digest,
+    )
+    .await;
+    if verdict == Verdict::Block {
+        let text = "The summary was withheld by the server's moderation settings".to_string();
+        util::edit_generated_message(ctx, command, text).await;
+        return;
+    }
+
+    let mut digest = format!(
+        "**Summary of the last {} messages**\n{}",
+        lines.len(),
+        link_markers(&digest, &lines, command.channel_id, command.guild_id)
+    );
+    if verdict == Verdict::Warn {
+        digest = format!("{}\n\n{}", openai::MODERATION_WARNING, digest);
+    }
+    util::edit_generated_long_message(ctx, command, digest).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_since("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_since(" 2h "), Some(Duration::hours(2)));
+        assert_eq!(parse_since("1d"), Some(Duration::days(1)));
+    }
+
+    #[test]
+    fn rejects_bad_input_without_panicking() {
+        for since in [
+            "",
+            "m",
+            "1",
+            "1日",
+            "日",
+            "1w",
+            "0m",
+            "-5h",
+            "31d",
+            "9999999999999d",
+        ] {
+            assert_eq!(parse_since(since), None, "{}", since);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use crate::commands::moderation::{self, Stage, Verdict};
+use crate::commands::openai;
+use crate::commands::registry::SubCommand;
+use crate::commands::{quota, util};
+use crate::http::{self, HttpClient, HttpError};
+use crate::llm::{self, transcription, ChatMessage};
+use serenity::async_trait;
+use serenity::builder::CreateApplicationCommandOption;
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::id::AttachmentId;
+use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+
+/// Whisper rejects files larger than 25 MB
+const MAX_AUDIO_BYTES: u64 = 25 * 1024 * 1024;
+/// File extensions Whisper understands, Discord voice messages are `.ogg`
+const AUDIO_FORMATS: [&str; 10] = [
+    "flac", "m4a", "mp3", "mp4", "mpeg", "mpga", "oga", "ogg", "wav", "webm",
+];
+
+const SUMMARY_INSTRUCTION: &str =
+    "Summarize this transcript of an audio recording in a few bullet points.";
+
+/// Returns the extension of a supported audio file
+fn audio_format(filename: &str) -> Option<String> {
+    let (_, extension) = filename.rsplit_once('.')?;
+    let extension = extension.to_lowercase();
+
+    AUDIO_FORMATS
+        .contains(&extension.as_str())
+        .then_some(extension)
+}
+
+async fn download(http: &HttpClient, url: &str) -> Result<Vec<u8>, HttpError> {
+    let audio = http.get(url).await?.error_for_status()?.bytes().await?;
+    Ok(audio.to_vec())
+}
+
+/// `/ai transcribe`
+pub struct Transcribe;
+
+#[async_trait]
+impl SubCommand for Transcribe {
+    fn name(&self) -> &str {
+        "transcribe"
+    }
+
+    fn description(&self) -> &str {
+        "Write out what is said in an audio file or voice message"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("attachment")
+                    .description("The audio to transcribe, up to 25 MB")
+                    .kind(CommandOptionType::Attachment)
+                    .required(true)
+            })
+            .create_sub_option(|sub_option| {
+                sub_option
+                    .name("summarize")
+                    .description("Add a short summary after the transcript")
+                    .kind(CommandOptionType::Boolean)
+            });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        transcribe(ctx, command).await
+    }
+}
+
+/// Writes out what is said in an audio attachment or voice message, optionally with a summary
+pub async fn transcribe(ctx: Context, command: ApplicationCommandInteraction) {
+    let attachment = util::get_sub_option(&command, "attachment")
+        .and_then(|value| value.as_str())
+        .and_then(|id| id.parse::<u64>().ok())
+        .and_then(|id| command.data.resolved.attachments.get(&AttachmentId(id)))
+        .cloned();
+    let attachment = match attachment {
+        Some(attachment) => attachment,
+        None => {
+            util::generate_ephemeral_message(ctx, command, "Could not find the audio".to_string())
+                .await;
+            return;
+        }
+    };
+    let summarize = util::get_sub_option(&command, "summarize")
+        .and_then(|value| value.as_bool())
+        .unwrap_or(false);
+
+    if audio_format(&attachment.filename).is_none() {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            format!("Only {} files can be transcribed", AUDIO_FORMATS.join(", ")),
+        )
+        .await;
+        return;
+    }
+    if attachment.size > MAX_AUDIO_BYTES {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            format!(
+                "The audio is {:.1} MB, only files up to 25 MB can be transcribed",
+                attachment.size as f64 / (1024.0 * 1024.0)
+            ),
+        )
+        .await;
+        return;
+    }
+
+    if let Err(reason) = quota::acquire(&ctx, command.guild_id, command.user.id).await {
+        util::generate_ephemeral_message(ctx, command, reason).await;
+        return;
+    }
+
+    // Downloading and transcribing a recording takes a while
+    if !util::defer(&ctx, &command, false).await {
+        quota::refund(&ctx, command.guild_id, command.user.id).await;
+        return;
+    }
+
+    let http = http::get(&ctx).await;
+    let audio = match download(&http, &attachment.url).await {
+        Ok(audio) => audio,
+        Err(why) => {
+            println!("Cannot download audio attachment: {}", why);
+            quota::refund(&ctx, command.guild_id, command.user.id).await;
+            let text = "Could not download the audio".to_string();
+            util::edit_generated_message(ctx, command, text).await;
+            return;
+        }
+    };
+
+    let transcriber = transcription::get(&ctx).await;
+    let transcript = match transcriber.transcribe(audio, &attachment.filename).await {
+        Ok(transcript) => transcript,
+        Err(why) => {
+            println!("Cannot transcribe audio: {}", why);
+            quota::refund(&ctx, command.guild_id, command.user.id).await;
+            let text = "Could not transcribe the audio :(".to_string();
+            util::edit_generated_message(ctx, command, text).await;
+            return;
+        }
+    };
+    if transcript.is_empty() {
+        quota::refund(&ctx, command.guild_id, command.user.id).await;
+        let text = "No speech was found in the audio".to_string();
+        util::edit_generated_message(ctx, command, text).await;
+        return;
+    }
+
+    let verdict = moderation::review(
+        &ctx,
+        command.guild_id,
+        command.user.id,
+        command.channel_id,
+        Stage::Prompt,
+        &transcript,
+    )
+    .await;
+    if verdict == Verdict::Block {
+        quota::refund(&ctx, command.guild_id, command.user.id).await;
+        let text = "The transcript was withheld by the server's moderation settings".to_string();
+        util::edit_generated_message(ctx, command, text).await;
+        return;
+    }
+
+    let mut content = format!("**Transcript of {}**\n{}", attachment.filename, transcript);
+    if summarize {
+        let options = openai::completion_options(&ctx, command.guild_id).await;
+        let messages = [ChatMessage::user(format!(
+            "{}\n\n{}",
+            SUMMARY_INSTRUCTION, transcript
+        ))];
+        let backend = llm::get(&ctx).await;
+        match backend.complete(&messages, &options, &[]).await {
+            Ok(completion) => {
+                let usage = completion
+                    .usage
+                    .unwrap_or_else(|| openai::estimate_usage(&messages, &completion.text));
+                openai::account(
+                    &ctx,
+                    command.guild_id,
+                    command.user.id,
+                    &options.model,
+                    usage,
+                )
+                .await;
+                content = format!("{}\n\n**Summary**\n{}", content, completion.text);
+            }
+            Err(why) => {
+                println!("Cannot reach the AI backend: {}", why);
+                content = format!("{}\n\n*Could not summarize the transcript*", content);
+            }
+        }
+    }
+    if verdict == Verdict::Warn {
+        content = format!("{}\n\n{}", openai::MODERATION_WARNING, content);
+    }
+
+    util::edit_generated_long_message(ctx, command, content).await
+}
@@ -0,0 +1,293 @@
+use crate::commands::registry::SubCommand;
+use crate::commands::util;
+use crate::llm::Usage;
+use crate::store;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use serenity::builder::CreateApplicationCommandOption;
+use serenity::client::Context;
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::id::{GuildId, UserId};
+use serenity::model::mention::Mentionable;
+use serenity::model::permissions::Permissions;
+use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+use std::collections::{BTreeMap, HashMap};
+
+/// Records older than this are dropped, the longest report period only needs a month
+const RETENTION_DAYS: i64 = 90;
+const TOP_USERS: usize = 5;
+/// US dollars per 1000 prompt and completion tokens, the first model name matching a prefix wins
+/// so more specific names have to come first
+const PRICES: [(&str, f64, f64); 6] = [
+    ("gpt-4o-mini", 0.00015, 0.0006),
+    ("gpt-4o", 0.005, 0.015),
+    ("gpt-4-turbo", 0.01, 0.03),
+    ("gpt-4-32k", 0.06, 0.12),
+    ("gpt-4", 0.03, 0.06),
+    ("gpt-3.5-turbo", 0.0015, 0.002),
+];
+
+/// A single completion, kept to report usage and cost with `/ai usage`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub user_id: UserId,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub time: DateTime<Utc>,
+}
+
+impl UsageRecord {
+    /// Estimated cost in US dollars, `None` for models without a known price such as local ones
+    fn cost(&self) -> Option<f64> {
+        let (_, prompt, completion) = PRICES
+            .iter()
+            .find(|(model, _, _)| self.model.starts_with(model))?;
+
+        Some(
+            (self.prompt_tokens as f64 * prompt + self.completion_tokens as f64 * completion)
+                / 1000.0,
+        )
+    }
+}
+
+/// Stores a completion's token counts with the server, or with the user for direct messages
+pub async fn record(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    user_id: UserId,
+    model: &str,
+    usage: Usage,
+) {
+    let now = Utc::now();
+    let store = store::get(ctx).await;
+
+    store
+        .write(|data| {
+            let records = match guild_id {
+                Some(guild_id) => data.usage_records.entry(guild_id).or_default(),
+                None => data.direct_usage_records.entry(user_id).or_default(),
+            };
+            records.retain(|record| now - record.time < Duration::days(RETENTION_DAYS));
+            records.push(UsageRecord {
+                user_id,
+                model: model.to_string(),
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                time: now,
+            });
+        })
+        .await
+}
+
+/// Adds up the records of each day, oldest first
+fn per_day(records: &[UsageRecord]) -> BTreeMap<NaiveDate, Totals> {
+    let mut days: BTreeMap<NaiveDate, Totals> = BTreeMap::new();
+    for record in records {
+        days.entry(record.time.date_naive())
+            .or_default()
+            .add(record);
+    }
+    days
+}
+
+#[derive(Default)]
+struct Totals {
+    requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost: f64,
+    /// Some of the requests used models without a known price
+    unpriced: bool,
+}
+
+impl Totals {
+    fn add(&mut self, record: &UsageRecord) {
+        self.requests += 1;
+        self.prompt_tokens += record.prompt_tokens;
+        self.completion_tokens += record.completion_tokens;
+        match record.cost() {
+            Some(cost) => self.cost += cost,
+            None => self.unpriced = true,
+        }
+    }
+
+    fn tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    fn format_cost(&self) -> String {
+        let marker = if self.unpriced { "+" } else { "" };
+        format!("${:.2}{}", self.cost, marker)
+    }
+}
+
+/// `/ai usage`
+pub struct UsageReport;
+
+#[async_trait]
+impl SubCommand for UsageReport {
+    fn name(&self) -> &str {
+        "usage"
+    }
+
+    fn description(&self) -> &str {
+        "Show the AI usage and estimated cost of this server"
+    }
+
+    fn options(&self, option: &mut CreateApplicationCommandOption) {
+        option.create_sub_option(|sub_option| {
+            sub_option
+                .name("period")
+                .description("How far back to look, a week when left out")
+                .kind(CommandOptionType::String)
+                .add_string_choice("Day", "day")
+                .add_string_choice("Week", "week")
+                .add_string_choice("Month", "month")
+        });
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        report(ctx, command).await
+    }
+}
+
+/// Shows the requests, tokens and estimated cost of this server, with its heaviest users. In
+/// direct messages it shows the user's own usage there.
+pub async fn report(ctx: Context, command: ApplicationCommandInteraction) {
+    if command.guild_id.is_some() && !util::has_permission(&command, Permissions::MANAGE_GUILD) {
+        util::generate_ephemeral_message(
+            ctx,
+            command,
+            "You need the Manage Server permission to view AI usage".to_string(),
+        )
+        .await;
+        return;
+    }
+
+    let period = util::get_sub_option(&command, "period")
+        .and_then(|value| value.as_str())
+        .unwrap_or("week")
+        .to_string();
+    let (label, days) = match period.as_str() {
+        "day" => ("the last day", 1),
+        "month" => ("the last 30 days", 30),
+        _ => ("the last 7 days", 7),
+    };
+    let since = Utc::now() - Duration::days(days);
+
+    let store = store::get(&ctx).await;
+    let records: Vec<UsageRecord> = store
+        .read(|data| {
+            let records = match command.guild_id {
+                Some(guild_id) => data.usage_records.get(&guild_id),
+                None => data.direct_usage_records.get(&command.user.id),
+            };
+            records
+                .map(|records| {
+                    records
+                        .iter()
+                        .filter(|record| record.time >= since)
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default()
+        })
+        .await;
+
+    if records.is_empty() {
+        util::generate_ephemeral_message(ctx, command, format!("No AI usage in {}", label)).await;
+        return;
+    }
+
+    let mut total = Totals::default();
+    let mut users: HashMap<UserId, Totals> = HashMap::new();
+    for record in &records {
+        total.add(record);
+        users.entry(record.user_id).or_default().add(record);
+    }
+
+    let mut content = format!(
+        "**AI usage in {}**\n**Requests:** {}\n**Tokens:** {} ({} prompt, {} completion)\n**Estimated cost:** {}\n",
+        label,
+        total.requests,
+        total.tokens(),
+        total.prompt_tokens,
+        total.completion_tokens,
+        total.format_cost()
+    );
+    // Direct messages only have the user's own usage
+    if command.guild_id.is_some() {
+        let mut top: Vec<(UserId, Totals)> = users.into_iter().collect();
+        top.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.tokens()));
+
+        content.push_str("\n**Top users**\n");
+        for (i, (user_id, totals)) in top.iter().take(TOP_USERS).enumerate() {
+            content.push_str(&format!(
+                "{}. {} — {} requests, {} tokens, {}\n",
+                i + 1,
+                user_id.mention(),
+                totals.requests,
+                totals.tokens(),
+                totals.format_cost()
+            ));
+        }
+    }
+    if days > 1 {
+        content.push_str("\n**Per day (UTC)**\n");
+        for (day, totals) in per_day(&records) {
+            content.push_str(&format!(
+                "{} — {} requests, {} tokens, {}\n",
+                day.format("%b %-d"),
+                totals.requests,
+                totals.tokens(),
+                totals.format_cost()
+            ));
+        }
+    }
+    if total.unpriced {
+        content.push_str("\n+ Some requests used models without a known price");
+    }
+
+    util::generate_ephemeral_long_message(ctx, command, content).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(user_id: u64, model: &str, tokens: u64, time: &str) -> UsageRecord {
+        UsageRecord {
+            user_id: UserId(user_id),
+            model: model.to_string(),
+            prompt_tokens: tokens,
+            completion_tokens: tokens,
+            time: time.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn adds_up_per_day() {
+        let records = vec![
+            record(2, "gpt-4o", 1000, "2024-03-02T23:59:00Z"),
+            record(1, "gpt-4o", 500, "2024-03-01T08:00:00Z"),
+            record(1, "llama3", 10, "2024-03-02T00:00:00Z"),
+            record(1, "gpt-4o", 500, "2024-03-01T20:00:00Z"),
+        ];
+
+        let days: Vec<(NaiveDate, Totals)> = per_day(&records).into_iter().collect();
+        assert_eq!(days.len(), 2);
+
+        let (day, totals) = &days[0];
+        assert_eq!(*day, NaiveDate::from_ymd_opt(2024, 3, 1).unwrap());
+        assert_eq!(totals.requests, 2);
+        assert_eq!(totals.tokens(), 2000);
+        assert_eq!(totals.format_cost(), "$0.02");
+
+        let (_, totals) = &days[1];
+        assert_eq!(totals.requests, 2);
+        assert_eq!(totals.tokens(), 2020);
+        assert_eq!(totals.format_cost(), "$0.02+");
+    }
+}
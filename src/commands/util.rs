@@ -1,7 +1,36 @@
+use crate::commands::registry::SlashCommand;
+use serenity::async_trait;
 use serenity::builder::CreateEmbed;
 use serenity::client::Context;
+use serenity::json::Value;
+use serenity::model::application::command::CommandOptionType;
 use serenity::model::application::interaction::InteractionResponseType;
+use serenity::model::channel::AttachmentType;
+use serenity::model::id::ChannelId;
+use serenity::model::permissions::Permissions;
 use serenity::model::prelude::interaction::application_command::ApplicationCommandInteraction;
+use std::future::Future;
+use std::time::Duration;
+
+/// How long a command may take before its response is deferred, Discord gives up after 3 seconds
+const DEFER_AFTER: Duration = Duration::from_millis(2000);
+
+pub struct Ping;
+
+#[async_trait]
+impl SlashCommand for Ping {
+    fn name(&self) -> &str {
+        "ping"
+    }
+
+    fn description(&self) -> &str {
+        "A ping command to verify if the bot is accepting comands"
+    }
+
+    async fn run(&self, ctx: Context, command: ApplicationCommandInteraction) {
+        ping(ctx, command).await
+    }
+}
 
 pub async fn ping(ctx: Context, command: ApplicationCommandInteraction) {
     let content = "Hey, I'm alive!".to_string();
@@ -26,6 +55,64 @@ pub async fn generate_message(
     }
 }
 
+/// Responds with a message only visible to the user who ran the command
+pub async fn generate_ephemeral_message(
+    ctx: Context,
+    command: ApplicationCommandInteraction,
+    content: String,
+) {
+    if let Err(why) = command
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|message| message.content(content).ephemeral(true))
+        })
+        .await
+    {
+        println!("Cannot respond to slash command: {}", why);
+    }
+}
+
+/// Tells Discord the answer is on its way, showing the bot as thinking until the response is
+/// edited in and giving it 15 minutes instead of 3 seconds. Returns false when the command can no
+/// longer be answered.
+pub async fn defer(
+    ctx: &Context,
+    command: &ApplicationCommandInteraction,
+    ephemeral: bool,
+) -> bool {
+    if let Err(why) = command
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                .interaction_response_data(|message| message.ephemeral(ephemeral))
+        })
+        .await
+    {
+        println!("Cannot defer slash command: {}", why);
+        return false;
+    }
+    true
+}
+
+/// Waits for what a command needs, deferring the response if that takes too long. Also returns
+/// whether the response was deferred, it then has to be edited in instead of sent.
+pub async fn defer_if_slow<F: Future>(
+    ctx: &Context,
+    command: &ApplicationCommandInteraction,
+    future: F,
+) -> (F::Output, bool) {
+    tokio::pin!(future);
+
+    tokio::select! {
+        output = &mut future => (output, false),
+        _ = tokio::time::sleep(DEFER_AFTER) => {
+            let deferred = defer(ctx, command, false).await;
+            (future.await, deferred)
+        }
+    }
+}
+
 pub async fn edit_generated_message(
     ctx: Context,
     command: ApplicationCommandInteraction,
@@ -39,6 +126,245 @@ pub async fn edit_generated_message(
     }
 }
 
+/// Discord's maximum message length
+pub const MESSAGE_LIMIT: usize = 2000;
+/// Answers needing more messages than this are sent as a file instead
+const MAX_MESSAGES: usize = 4;
+
+/// Sends a follow-up message to an interaction that has already been responded to
+pub async fn generate_followup_message(
+    ctx: Context,
+    command: ApplicationCommandInteraction,
+    content: String,
+) {
+    if let Err(why) = command
+        .create_followup_message(&ctx.http, |message| message.content(content))
+        .await
+    {
+        println!("Cannot send follow-up message: {}", why);
+    }
+}
+
+/// Puts text of any length into the interaction response, continuing in follow-up messages or
+/// attaching it as a markdown file when it is too long
+pub async fn edit_generated_long_message(
+    ctx: Context,
+    command: ApplicationCommandInteraction,
+    content: String,
+) {
+    let chunks = split_message(&content, MESSAGE_LIMIT);
+
+    if chunks.len() > MAX_MESSAGES {
+        let file = AttachmentType::Bytes {
+            data: content.into_bytes().into(),
+            filename: "answer.md".to_string(),
+        };
+
+        edit_generated_message(
+            ctx.to_owned(),
+            command.to_owned(),
+            "The answer is too long for Discord, so it is attached as a file".to_string(),
+        )
+        .await;
+
+        if let Err(why) = command
+            .create_followup_message(&ctx.http, |message| message.add_file(file))
+            .await
+        {
+            println!("Cannot send follow-up message: {}", why);
+        }
+        return;
+    }
+
+    let mut chunks = chunks.into_iter();
+    let first = chunks.next().unwrap_or_default();
+    edit_generated_message(ctx.to_owned(), command.to_owned(), first).await;
+
+    for chunk in chunks {
+        generate_followup_message(ctx.to_owned(), command.to_owned(), chunk).await;
+    }
+}
+
+/// Responds with text of any length only visible to the user who ran the command, continuing in
+/// follow-up messages
+pub async fn generate_ephemeral_long_message(
+    ctx: Context,
+    command: ApplicationCommandInteraction,
+    content: String,
+) {
+    let mut chunks = split_message(&content, MESSAGE_LIMIT).into_iter();
+    let first = chunks.next().unwrap_or_default();
+    generate_ephemeral_message(ctx.to_owned(), command.to_owned(), first).await;
+
+    for chunk in chunks {
+        if let Err(why) = command
+            .create_followup_message(&ctx.http, |message| message.content(chunk).ephemeral(true))
+            .await
+        {
+            println!("Cannot send follow-up message: {}", why);
+        }
+    }
+}
+
+/// Edits text of any length into an ephemeral deferred response, continuing in follow-up messages
+/// only the user can see as well
+pub async fn edit_generated_ephemeral_long_message(
+    ctx: Context,
+    command: ApplicationCommandInteraction,
+    content: String,
+) {
+    let mut chunks = split_message(&content, MESSAGE_LIMIT).into_iter();
+    let first = chunks.next().unwrap_or_default();
+    edit_generated_message(ctx.to_owned(), command.to_owned(), first).await;
+
+    for chunk in chunks {
+        if let Err(why) = command
+            .create_followup_message(&ctx.http, |message| message.content(chunk).ephemeral(true))
+            .await
+        {
+            println!("Cannot send follow-up message: {}", why);
+        }
+    }
+}
+
+/// Sends text of any length to a channel, split over several messages or attached as a file
+pub async fn send_long_message(ctx: &Context, channel_id: ChannelId, content: String) {
+    let chunks = split_message(&content, MESSAGE_LIMIT);
+
+    if chunks.len() > MAX_MESSAGES {
+        let file = AttachmentType::Bytes {
+            data: content.into_bytes().into(),
+            filename: "answer.md".to_string(),
+        };
+
+        if let Err(why) = channel_id
+            .send_message(&ctx.http, |message| {
+                message
+                    .content("The answer is too long for Discord, so it is attached as a file")
+                    .add_file(file)
+            })
+            .await
+        {
+            println!("Cannot send message: {}", why);
+        }
+        return;
+    }
+
+    for chunk in chunks {
+        if let Err(why) = channel_id.say(&ctx.http, chunk).await {
+            println!("Cannot send message: {}", why);
+        }
+    }
+}
+
+/// Splits markdown into pieces of at most `limit` characters. Breaks happen between lines and
+/// outside of code blocks where possible, a code block too long for one piece is closed at the
+/// end of each piece and opened again in the next so it keeps rendering as code.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    // Group the lines into blocks, each code block staying together as a single one
+    let mut blocks: Vec<String> = Vec::new();
+    let mut fence: Option<String> = None;
+
+    for line in text.lines() {
+        let is_fence = line.trim_start().starts_with("```");
+        // A fence closed on the same line, such as ```x```, is inline code rather than a block
+        let opens_block = is_fence && !line.trim_start()[3..].contains("```");
+
+        match fence.as_mut() {
+            Some(block) => {
+                block.push('\n');
+                block.push_str(line);
+                if is_fence {
+                    blocks.extend(fence.take());
+                }
+            }
+            None if opens_block => fence = Some(line.to_string()),
+            None => blocks.push(line.to_string()),
+        }
+    }
+    // An unterminated code block is closed so it doesn't swallow the following messages
+    if let Some(mut block) = fence {
+        block.push_str("\n```");
+        blocks.push(block);
+    }
+
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for piece in blocks.iter().flat_map(|block| split_block(block, limit)) {
+        if !current.is_empty() && current.chars().count() + 1 + piece.chars().count() > limit {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(&piece);
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+/// Splits a single line or code block that doesn't fit in one message
+fn split_block(block: &str, limit: usize) -> Vec<String> {
+    if block.chars().count() <= limit {
+        return vec![block.to_string()];
+    }
+
+    if !block.trim_start().starts_with("```") || !block.contains('\n') {
+        return split_text(block, limit);
+    }
+
+    let mut lines: Vec<&str> = block.lines().collect();
+    let opening = lines.remove(0);
+    lines.pop();
+
+    // Leave room to open and close the code block in every piece
+    let budget = limit.saturating_sub(opening.chars().count() + 5).max(1);
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+
+    for line in lines.iter().flat_map(|line| split_text(line, budget)) {
+        if !current.is_empty() && current.chars().count() + 1 + line.chars().count() > budget {
+            pieces.push(format!("{}\n{}\n```", opening, current));
+            current.clear();
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(&line);
+    }
+    pieces.push(format!("{}\n{}\n```", opening, current));
+
+    pieces
+}
+
+/// Splits a single line at whitespace, or mid-word when there is none
+fn split_text(text: &str, limit: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut rest: Vec<char> = text.chars().collect();
+
+    while rest.len() > limit {
+        let end = rest[..limit]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .filter(|end| *end > 0)
+            .unwrap_or(limit);
+
+        pieces.push(rest.drain(..end).collect::<String>().trim_end().to_string());
+        while rest.first().is_some_and(|c| c.is_whitespace()) {
+            rest.remove(0);
+        }
+    }
+    if !rest.is_empty() || pieces.is_empty() {
+        pieces.push(rest.into_iter().collect());
+    }
+
+    pieces
+}
+
 pub async fn generate_embed_message(
     ctx: Context,
     command: ApplicationCommandInteraction,
@@ -56,3 +382,173 @@ pub async fn generate_embed_message(
         println!("Cannot respond to slash command: {}", why);
     }
 }
+
+/// Puts an embed into a response that was deferred
+pub async fn edit_generated_embed_message(
+    ctx: Context,
+    command: ApplicationCommandInteraction,
+    embed: CreateEmbed,
+) {
+    if let Err(why) = command
+        .edit_original_interaction_response(&ctx.http, |response| response.set_embed(embed))
+        .await
+    {
+        println!("Cannot edit response: {}", why);
+    }
+}
+
+/// Sends the message, or edits it in when the response was deferred by `defer_if_slow`
+pub async fn generate_or_edit_message(
+    ctx: Context,
+    command: ApplicationCommandInteraction,
+    content: String,
+    deferred: bool,
+) {
+    match deferred {
+        true => edit_generated_message(ctx, command, content).await,
+        false => generate_message(ctx, command, content).await,
+    }
+}
+
+/// Sends the embed, or edits it in when the response was deferred by `defer_if_slow`
+pub async fn generate_or_edit_embed_message(
+    ctx: Context,
+    command: ApplicationCommandInteraction,
+    embed: CreateEmbed,
+    deferred: bool,
+) {
+    match deferred {
+        true => edit_generated_embed_message(ctx, command, embed).await,
+        false => generate_embed_message(ctx, command, embed).await,
+    }
+}
+
+/// Returns the value of an option nested under the selected subcommand
+pub fn get_sub_option<'a>(
+    command: &'a ApplicationCommandInteraction,
+    name: &str,
+) -> Option<&'a Value> {
+    command
+        .data
+        .options
+        .first()?
+        .options
+        .iter()
+        .find(|option| option.name == name)?
+        .value
+        .as_ref()
+}
+
+/// Returns the value of an option nested under the selected subcommand group and subcommand
+pub fn get_group_option<'a>(
+    command: &'a ApplicationCommandInteraction,
+    name: &str,
+) -> Option<&'a Value> {
+    command
+        .data
+        .options
+        .first()
+        .filter(|group| group.kind == CommandOptionType::SubCommandGroup)?
+        .options
+        .first()?
+        .options
+        .iter()
+        .find(|option| option.name == name)?
+        .value
+        .as_ref()
+}
+
+/// Checks the permissions the invoking member has in the channel the command was used in
+pub fn has_permission(command: &ApplicationCommandInteraction, permission: Permissions) -> bool {
+    command
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.contains(permission))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_fits(chunks: &[String], limit: usize) {
+        for chunk in chunks {
+            assert!(chunk.chars().count() <= limit, "{:?} is too long", chunk);
+            assert_eq!(
+                chunk.matches("```").count() % 2,
+                0,
+                "{:?} leaves a fence open",
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_short_messages_whole() {
+        assert_eq!(split_message("one\ntwo", 100), vec!["one\ntwo"]);
+    }
+
+    #[test]
+    fn splits_a_code_block_spanning_the_limit() {
+        let code: Vec<String> = (0..20).map(|i| format!("let x{} = {};", i, i)).collect();
+        let text = format!("Here you go:\n```rust\n{}\n```\nDone", code.join("\n"));
+
+        let chunks = split_message(&text, 80);
+        assert!(chunks.len() > 1);
+        assert_fits(&chunks, 80);
+        assert_eq!(chunks[0], "Here you go:");
+        assert!(chunks.last().unwrap().ends_with("\n```\nDone"));
+
+        // Every line of code arrives once, in order
+        let lines: Vec<&str> = chunks
+            .iter()
+            .flat_map(|chunk| chunk.lines())
+            .filter(|line| line.starts_with("let "))
+            .collect();
+        assert_eq!(lines, code);
+    }
+
+    #[test]
+    fn reopens_the_fence_language_in_the_next_chunk() {
+        let code = "print('hello world')\n".repeat(10);
+        let text = format!("```python\n{}```", code);
+
+        let chunks = split_message(&text, 100);
+        assert!(chunks.len() > 1);
+        assert_fits(&chunks, 100);
+        for chunk in &chunks {
+            assert!(
+                chunk.starts_with("```python\n"),
+                "{:?} isn't highlighted",
+                chunk
+            );
+            assert!(chunk.ends_with("\n```"));
+        }
+    }
+
+    #[test]
+    fn splits_a_single_line_longer_than_the_limit() {
+        let line = "word ".repeat(30);
+        let chunks = split_message(line.trim_end(), 32);
+        assert!(chunks.len() > 1);
+        assert_fits(&chunks, 32);
+        assert_eq!(chunks.join(" "), line.trim_end());
+
+        // Without whitespace the line is cut mid-word
+        let chunks = split_message(&"x".repeat(70), 32);
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(),
+            vec![32, 32, 6]
+        );
+    }
+
+    #[test]
+    fn leaves_a_fence_closed_on_the_same_line_alone() {
+        let text = "Run ```x``` first\n```x```\nthen the rest";
+        assert_eq!(split_message(text, 100), vec![text]);
+
+        let chunks = split_message(&format!("```{}```", "y".repeat(40)), 20);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 20));
+        assert!(!chunks.concat().contains('\n'));
+    }
+}
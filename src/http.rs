@@ -0,0 +1,284 @@
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serenity::client::Context;
+use serenity::prelude::TypeMapKey;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest a whole request may take, requests that are known to be slow set their own timeout
+const TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_RETRIES: u32 = 3;
+/// Delay before the first retry, doubled for every retry after it
+const BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest a server may ask us to wait with `Retry-After`, past this the request fails instead
+const MAX_DELAY: Duration = Duration::from_secs(30);
+/// Failed requests in a row after which a host is left alone for a while
+const FAILURE_THRESHOLD: u32 = 5;
+const OPEN_DURATION: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum HttpError {
+    Request(reqwest::Error),
+    /// The host failed too often recently, so it wasn't asked
+    CircuitOpen(String),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::Request(why) => write!(f, "{}", why),
+            HttpError::CircuitOpen(host) => {
+                write!(f, "{} is failing, requests to it are paused", host)
+            }
+        }
+    }
+}
+
+impl From<reqwest::Error> for HttpError {
+    fn from(why: reqwest::Error) -> HttpError {
+        HttpError::Request(why)
+    }
+}
+
+/// Recent failures of a single host
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+    /// When the single request let through after the circuit was open was sent
+    probe_sent: Option<Instant>,
+}
+
+/// The connection pool every outbound request goes through, retrying rate limits and server
+/// errors and pausing requests to hosts that keep failing
+pub struct HttpClient {
+    client: Client,
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+/// Shared through `Context::data`
+pub struct Http;
+
+impl TypeMapKey for Http {
+    type Value = Arc<HttpClient>;
+}
+
+impl HttpClient {
+    pub fn new() -> HttpClient {
+        let client = Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(TIMEOUT)
+            .build()
+            .expect("Failed to build the HTTP client");
+
+        HttpClient {
+            client,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Builds requests that are then sent with `send`
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Response, HttpError> {
+        self.send(self.client.get(url)).await
+    }
+
+    /// Sends a request, retrying with backoff while the server is rate limiting or failing. The
+    /// last response is returned even when it is still an error so its body can be read.
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, HttpError> {
+        let mut request = request.build()?;
+        let host = request.url().host_str().unwrap_or_default().to_string();
+        self.allow(&host)?;
+
+        let mut attempt = 0;
+        loop {
+            // Streamed bodies such as file uploads can't be sent twice
+            let retry = request.try_clone().filter(|_| attempt < MAX_RETRIES);
+            let result = self.client.execute(request).await;
+
+            let delay = match &result {
+                Ok(response) if retryable(response.status()) => {
+                    retry_after(response).unwrap_or_else(|| backoff(attempt))
+                }
+                // Nothing reached the server, so it is always safe to try again
+                Err(why) if why.is_connect() => backoff(attempt),
+                Ok(_) => {
+                    self.record(&host, true);
+                    return result.map_err(HttpError::from);
+                }
+                Err(_) => {
+                    self.record(&host, false);
+                    return result.map_err(HttpError::from);
+                }
+            };
+
+            match retry {
+                Some(next) if delay <= MAX_DELAY => {
+                    tokio::time::sleep(delay).await;
+                    request = next;
+                    attempt += 1;
+                }
+                _ => {
+                    self.record(&host, false);
+                    return result.map_err(HttpError::from);
+                }
+            }
+        }
+    }
+
+    /// Fails fast while the host's circuit is open, letting a single request through once it
+    /// has been open long enough to see if the host recovered
+    fn allow(&self, host: &str) -> Result<(), HttpError> {
+        let mut breakers = self.breakers.lock().expect("HTTP breakers were poisoned");
+        let breaker = match breakers.get_mut(host) {
+            Some(breaker) => breaker,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        match breaker.open_until {
+            Some(open_until) if now < open_until => Err(HttpError::CircuitOpen(host.to_string())),
+            // Half open, everyone else waits for the probe unless it was dropped without an answer
+            Some(_)
+                if breaker
+                    .probe_sent
+                    .is_some_and(|sent| now < sent + OPEN_DURATION) =>
+            {
+                Err(HttpError::CircuitOpen(host.to_string()))
+            }
+            Some(_) => {
+                breaker.probe_sent = Some(now);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record(&self, host: &str, healthy: bool) {
+        let mut breakers = self.breakers.lock().expect("HTTP breakers were poisoned");
+        if healthy {
+            breakers.remove(host);
+            return;
+        }
+
+        let breaker = breakers.entry(host.to_string()).or_default();
+        breaker.failures += 1;
+        // A failed probe opens the circuit again right away
+        if breaker.probe_sent.take().is_some() {
+            breaker.open_until = Some(Instant::now() + OPEN_DURATION);
+        } else if breaker.failures >= FAILURE_THRESHOLD && breaker.open_until.is_none() {
+            println!(
+                "{} failed {} times in a row, pausing requests to it for {}s",
+                host,
+                breaker.failures,
+                OPEN_DURATION.as_secs()
+            );
+            breaker.open_until = Some(Instant::now() + OPEN_DURATION);
+        }
+    }
+}
+
+impl Default for HttpClient {
+    fn default() -> HttpClient {
+        HttpClient::new()
+    }
+}
+
+/// Rate limits and server errors are usually over soon
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Exponential backoff with jitter, so clients that failed together don't all retry together
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_DELAY * 2u32.pow(attempt);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+/// How long the server asked us to wait, given either in seconds or as a date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+/// Returns the client that was inserted into `Context::data` on startup
+pub async fn get(ctx: &Context) -> Arc<HttpClient> {
+    ctx.data
+        .read()
+        .await
+        .get::<Http>()
+        .expect("Expected Http in TypeMap")
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "ergast.com";
+
+    /// Pretends the circuit has been open long enough to let a probe through
+    fn expire(client: &HttpClient) {
+        let mut breakers = client.breakers.lock().unwrap();
+        breakers.get_mut(HOST).unwrap().open_until = Some(Instant::now());
+    }
+
+    #[test]
+    fn opens_after_repeated_failures() {
+        let client = HttpClient::new();
+        for _ in 0..FAILURE_THRESHOLD - 1 {
+            client.record(HOST, false);
+            assert!(client.allow(HOST).is_ok());
+        }
+        client.record(HOST, false);
+        assert!(matches!(client.allow(HOST), Err(HttpError::CircuitOpen(_))));
+        assert!(client.allow("api.openai.com").is_ok());
+    }
+
+    #[test]
+    fn lets_a_single_probe_through() {
+        let client = HttpClient::new();
+        for _ in 0..FAILURE_THRESHOLD {
+            client.record(HOST, false);
+        }
+        expire(&client);
+
+        assert!(client.allow(HOST).is_ok());
+        assert!(client.allow(HOST).is_err());
+        assert!(client.allow(HOST).is_err());
+
+        // A healthy probe closes the circuit
+        client.record(HOST, true);
+        assert!(client.allow(HOST).is_ok());
+        assert!(client.allow(HOST).is_ok());
+    }
+
+    #[test]
+    fn failed_probe_opens_again() {
+        let client = HttpClient::new();
+        for _ in 0..FAILURE_THRESHOLD {
+            client.record(HOST, false);
+        }
+        expire(&client);
+
+        assert!(client.allow(HOST).is_ok());
+        client.record(HOST, false);
+        assert!(client.allow(HOST).is_err());
+
+        expire(&client);
+        assert!(client.allow(HOST).is_ok());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::llm::{
+    ChatMessage, Completion, CompletionOptions, CompletionStream, LlmBackend, LlmError, Tool,
+    ToolCall, Usage,
+};
+use serde::Deserialize;
+use serenity::async_trait;
+use serenity::prelude::Mutex;
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+
+/// A single transparent pixel, returned for every generated image
+const PIXEL: [u8; 67] = [
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0a, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0x00, 0x01, 0x00, 0x00,
+    0x05, 0x00, 0x01, 0x0d, 0x0a, 0x2d, 0xb4, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae,
+    0x42, 0x60, 0x82,
+];
+
+/// A scripted turn of the mock model, written in JSON as `{"text": "..."}` or
+/// `{"tool_calls": [{"id": "...", "function": {"name": "...", "arguments": "{}"}}]}`
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MockReply {
+    Text(String),
+    ToolCalls(Vec<ToolCall>),
+}
+
+/// Answers without any model, for running the bot locally and in tests. Scripted replies are
+/// handed out in order, after that tool results or the last user message are echoed back.
+pub struct MockBackend {
+    replies: Mutex<VecDeque<MockReply>>,
+}
+
+impl MockBackend {
+    pub fn new(replies: Vec<MockReply>) -> MockBackend {
+        MockBackend {
+            replies: Mutex::new(replies.into()),
+        }
+    }
+
+    /// Reads the script from the JSON file at `LLM_MOCK_SCRIPT`, when set
+    pub fn from_env() -> MockBackend {
+        let replies = match env::var("LLM_MOCK_SCRIPT") {
+            Ok(path) => {
+                let text = fs::read_to_string(path).expect("Failed to read mock script");
+                serde_json::from_str(&text).expect("Failed to parse mock script")
+            }
+            Err(_) => Vec::new(),
+        };
+
+        MockBackend::new(replies)
+    }
+
+    async fn respond(&self, messages: &[ChatMessage]) -> MockReply {
+        if let Some(reply) = self.replies.lock().await.pop_front() {
+            return reply;
+        }
+
+        let results: Vec<&str> = messages
+            .iter()
+            .rev()
+            .take_while(|message| message.role == "tool")
+            .map(|message| message.content.as_str())
+            .collect();
+        if !results.is_empty() {
+            return MockReply::Text(format!("The tools said: {}", results.join(" ")));
+        }
+
+        let prompt = messages
+            .iter()
+            .rev()
+            .find(|message| message.role == "user")
+            .map(|message| message.content.as_str())
+            .unwrap_or_default();
+        MockReply::Text(format!("You said: {}", prompt))
+    }
+}
+
+#[async_trait]
+impl LlmBackend for MockBackend {
+    async fn complete(
+        &self,
+        messages: &[ChatMessage],
+        _options: &CompletionOptions,
+        _tools: &[Tool],
+    ) -> Result<Completion, LlmError> {
+        let (text, tool_calls) = match self.respond(messages).await {
+            MockReply::Text(text) => (text, Vec::new()),
+            MockReply::ToolCalls(tool_calls) => (String::new(), tool_calls),
+        };
+        let usage = count_usage(messages, &text);
+
+        Ok(Completion {
+            text,
+            tool_calls,
+            usage: Some(usage),
+        })
+    }
+
+    async fn stream(
+        &self,
+        messages: &[ChatMessage],
+        _options: &CompletionOptions,
+        _tools: &[Tool],
+    ) -> Result<Box<dyn CompletionStream>, LlmError> {
+        let (text, tool_calls) = match self.respond(messages).await {
+            MockReply::Text(text) => (text, Vec::new()),
+            MockReply::ToolCalls(tool_calls) => (String::new(), tool_calls),
+        };
+        let pieces = text.split_inclusive(' ').map(|piece| piece.to_string());
+
+        Ok(Box::new(MockStream {
+            pieces: pieces.collect(),
+            usage: count_usage(messages, &text),
+            tool_calls,
+        }))
+    }
+
+    async fn generate_image(&self, _prompt: &str, _size: &str) -> Result<Vec<u8>, LlmError> {
+        Ok(PIXEL.to_vec())
+    }
+}
+
+/// Counts every word as a token
+fn count_usage(messages: &[ChatMessage], text: &str) -> Usage {
+    Usage {
+        prompt_tokens: messages
+            .iter()
+            .map(|message| message.content.split_whitespace().count() as u64)
+            .sum(),
+        completion_tokens: text.split_whitespace().count() as u64,
+    }
+}
+
+struct MockStream {
+    pieces: VecDeque<String>,
+    usage: Usage,
+    tool_calls: Vec<ToolCall>,
+}
+
+#[async_trait]
+impl CompletionStream for MockStream {
+    async fn next(&mut self) -> Option<Result<String, LlmError>> {
+        self.pieces.pop_front().map(Ok)
+    }
+
+    fn usage(&self) -> Option<Usage> {
+        Some(self.usage)
+    }
+
+    fn tool_calls(&self) -> Vec<ToolCall> {
+        self.tool_calls.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::FunctionCall;
+
+    fn options() -> CompletionOptions {
+        CompletionOptions {
+            model: "mock".to_string(),
+            temperature: 0.0,
+            max_tokens: 100,
+            system_prompt: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_script_in_order() {
+        let script = r#"[
+            {"text": "First"},
+            {"tool_calls": [{"id": "1", "function": {"name": "calendar", "arguments": "{}"}}]},
+            {"text": "Second answer"}
+        ]"#;
+        let backend = MockBackend::new(serde_json::from_str(script).unwrap());
+        let messages = vec![ChatMessage::user("Hi there".to_string())];
+
+        let first = backend.complete(&messages, &options(), &[]).await.unwrap();
+        assert_eq!(first.text, "First");
+        assert!(first.tool_calls.is_empty());
+
+        let second = backend.complete(&messages, &options(), &[]).await.unwrap();
+        assert_eq!(second.text, "");
+        assert_eq!(second.tool_calls[0].function.name, "calendar");
+        assert_eq!(second.tool_calls[0].kind, "function");
+
+        let mut stream = backend.stream(&messages, &options(), &[]).await.unwrap();
+        let mut text = String::new();
+        while let Some(piece) = stream.next().await {
+            text.push_str(&piece.unwrap());
+        }
+        assert_eq!(text, "Second answer");
+        assert_eq!(stream.usage().unwrap().completion_tokens, 2);
+
+        // Once the script runs out the prompt is echoed back
+        let echo = backend.complete(&messages, &options(), &[]).await.unwrap();
+        assert_eq!(echo.text, "You said: Hi there");
+    }
+
+    #[tokio::test]
+    async fn echoes_tool_results() {
+        let backend = MockBackend::new(Vec::new());
+        let tool_call = ToolCall {
+            id: "1".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall::default(),
+        };
+        let messages = vec![
+            ChatMessage::user("Who leads?".to_string()),
+            ChatMessage::tool_calls(String::new(), vec![tool_call]),
+            ChatMessage::tool_result("1".to_string(), "Verstappen".to_string()),
+        ];
+
+        let completion = backend.complete(&messages, &options(), &[]).await.unwrap();
+        assert_eq!(completion.text, "The tools said: Verstappen");
+    }
+}
@@ -0,0 +1,313 @@
+pub mod mock;
+pub mod moderation;
+pub mod ollama;
+pub mod openai;
+#[cfg(test)]
+mod test_server;
+pub mod transcription;
+
+use crate::http::{HttpClient, HttpError};
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use serenity::async_trait;
+use serenity::client::Context;
+use serenity::prelude::TypeMapKey;
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Answers can take minutes to generate, much longer than other requests are allowed
+pub const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A single message of a conversation
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+    /// Functions the assistant asked to have called before it answers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The call a `tool` message is the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// URLs of images sent along with a user message to vision models
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+}
+
+impl ChatMessage {
+    fn new(role: &str, content: String) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+        }
+    }
+
+    pub fn system(content: String) -> ChatMessage {
+        ChatMessage::new("system", content)
+    }
+
+    pub fn user(content: String) -> ChatMessage {
+        ChatMessage::new("user", content)
+    }
+
+    /// A user message with pictures for the model to look at
+    pub fn user_with_images(content: String, images: Vec<String>) -> ChatMessage {
+        ChatMessage {
+            images,
+            ..ChatMessage::new("user", content)
+        }
+    }
+
+    pub fn assistant(content: String) -> ChatMessage {
+        ChatMessage::new("assistant", content)
+    }
+
+    /// The assistant's turn when it asked for tools instead of answering
+    pub fn tool_calls(content: String, tool_calls: Vec<ToolCall>) -> ChatMessage {
+        ChatMessage {
+            tool_calls,
+            ..ChatMessage::new("assistant", content)
+        }
+    }
+
+    /// The result of a tool call, sent back so the assistant can use it
+    pub fn tool_result(tool_call_id: String, content: String) -> ChatMessage {
+        ChatMessage {
+            tool_call_id: Some(tool_call_id),
+            ..ChatMessage::new("tool", content)
+        }
+    }
+
+    /// Rough token count, OpenAI averages about four characters per token plus some overhead and
+    /// charges at most 765 tokens for an image
+    pub fn estimated_tokens(&self) -> usize {
+        self.content.chars().count() / 4 + 4 + self.images.len() * 765
+    }
+}
+
+/// A function the assistant can ask to have called, described with a JSON schema
+pub struct Tool {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub parameters: Value,
+}
+
+impl Tool {
+    /// The tool in the format of the chat completions API
+    pub fn to_json(&self) -> Value {
+        json!({
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "description": self.description,
+                "parameters": self.parameters
+            }
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "function_kind")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments, which models don't always get right
+    pub arguments: String,
+}
+
+fn function_kind() -> String {
+    "function".to_string()
+}
+
+/// Settings used for a single completion request
+#[derive(Clone)]
+pub struct CompletionOptions {
+    pub model: String,
+    pub temperature: f64,
+    pub max_tokens: u64,
+    pub system_prompt: Option<String>,
+}
+
+impl CompletionOptions {
+    /// The system prompt, when there is one, followed by the conversation
+    pub fn with_system_prompt(&self, messages: &[ChatMessage]) -> Vec<ChatMessage> {
+        self.system_prompt
+            .iter()
+            .map(|prompt| ChatMessage::system(prompt.to_string()))
+            .chain(messages.iter().cloned())
+            .collect()
+    }
+}
+
+/// Tokens a completion used, as reported by the backend
+#[derive(Clone, Copy, Default)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+pub struct Completion {
+    pub text: String,
+    /// When not empty the assistant wants these results before it answers
+    pub tool_calls: Vec<ToolCall>,
+    /// Not every OpenAI compatible server reports usage
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug)]
+pub enum LlmError {
+    Http(HttpError),
+    /// The backend answered, but not with anything usable
+    Response(String),
+    /// The backend has no way of doing what was asked
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::Http(why) => write!(f, "request failed: {}", why),
+            LlmError::Response(why) => write!(f, "unexpected response: {}", why),
+            LlmError::Unsupported(feature) => write!(f, "{} is not supported", feature),
+        }
+    }
+}
+
+impl From<HttpError> for LlmError {
+    fn from(why: HttpError) -> LlmError {
+        LlmError::Http(why)
+    }
+}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(why: reqwest::Error) -> LlmError {
+        LlmError::Http(HttpError::Request(why))
+    }
+}
+
+/// A completion whose text arrives in pieces while it is generated
+#[async_trait]
+pub trait CompletionStream: Send {
+    /// Returns the next piece of generated text, `None` once the completion has finished
+    async fn next(&mut self) -> Option<Result<String, LlmError>>;
+
+    /// Tokens used, only known once the stream has finished and only if the backend reported them
+    fn usage(&self) -> Option<Usage>;
+
+    /// Tools the assistant asked for, only complete once the stream has finished
+    fn tool_calls(&self) -> Vec<ToolCall>;
+}
+
+/// A service that can generate chat completions, optionally calling the given tools
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    async fn complete(
+        &self,
+        messages: &[ChatMessage],
+        options: &CompletionOptions,
+        tools: &[Tool],
+    ) -> Result<Completion, LlmError>;
+
+    async fn stream(
+        &self,
+        messages: &[ChatMessage],
+        options: &CompletionOptions,
+        tools: &[Tool],
+    ) -> Result<Box<dyn CompletionStream>, LlmError>;
+
+    /// Generates an image for the prompt, `size` being e.g. `512x512`, and returns it as a PNG
+    async fn generate_image(&self, _prompt: &str, _size: &str) -> Result<Vec<u8>, LlmError> {
+        Err(LlmError::Unsupported("image generation"))
+    }
+}
+
+/// The backend all AI commands go through, shared through `Context::data`
+pub struct Llm;
+
+impl TypeMapKey for Llm {
+    type Value = Arc<dyn LlmBackend>;
+}
+
+/// Picks the backend from `LLM_BACKEND`: `openai` (default), `compatible`, `ollama` or `mock`
+pub fn from_env(http: Arc<HttpClient>) -> Arc<dyn LlmBackend> {
+    let base_url = env::var("LLM_BASE_URL").ok();
+    let api_key = env::var("LLM_API_KEY").ok();
+
+    match env::var("LLM_BACKEND").as_deref() {
+        Ok("compatible") => Arc::new(openai::OpenAiBackend::compatible(
+            http,
+            base_url.expect("Expected LLM_BASE_URL for an OpenAI compatible backend"),
+            api_key,
+        )),
+        Ok("ollama") => Arc::new(ollama::OllamaBackend::new(http, base_url)),
+        Ok("mock") => Arc::new(mock::MockBackend::from_env()),
+        _ => {
+            let token = env::var("OPENAI_API_KEY").expect("Expected a token in the environment");
+            Arc::new(openai::OpenAiBackend::openai(http, token))
+        }
+    }
+}
+
+/// Returns the backend that was inserted into `Context::data` on startup
+pub async fn get(ctx: &Context) -> Arc<dyn LlmBackend> {
+    ctx.data
+        .read()
+        .await
+        .get::<Llm>()
+        .expect("Expected Llm in TypeMap")
+        .clone()
+}
+
+/// Splits a streamed response body into lines, only handing out complete ones so characters
+/// split across chunks stay intact
+pub struct LineReader {
+    response: Response,
+    buffer: Vec<u8>,
+}
+
+impl LineReader {
+    pub fn new(response: Response) -> LineReader {
+        LineReader {
+            response,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub async fn next_line(&mut self) -> Option<Result<String, reqwest::Error>> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                return Some(Ok(String::from_utf8_lossy(&line).trim().to_string()));
+            }
+
+            match self.response.chunk().await {
+                Ok(Some(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Ok(None) if self.buffer.is_empty() => return None,
+                // The body may not end with a newline
+                Ok(None) => {
+                    let line: Vec<u8> = self.buffer.drain(..).collect();
+                    return Some(Ok(String::from_utf8_lossy(&line).trim().to_string()));
+                }
+                Err(why) => return Some(Err(why)),
+            }
+        }
+    }
+}
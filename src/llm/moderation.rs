@@ -0,0 +1,127 @@
+use crate::http::HttpClient;
+use crate::llm::LlmError;
+use regex::{Regex, RegexBuilder};
+use serde_json::{json, Value};
+use serenity::client::Context;
+use serenity::prelude::TypeMapKey;
+use std::env;
+use std::fs;
+use std::sync::Arc;
+
+const MODERATIONS_URL: &str = "https://api.openai.com/v1/moderations";
+
+/// Decides whether text is allowed, using a local list of patterns and optionally OpenAI's
+/// moderations endpoint
+pub struct Moderator {
+    patterns: Vec<Regex>,
+    openai: Option<(Arc<HttpClient>, String)>,
+}
+
+/// Shared through `Context::data`
+pub struct Moderation;
+
+impl TypeMapKey for Moderation {
+    type Value = Arc<Moderator>;
+}
+
+impl Moderator {
+    /// `MODERATION` picks `openai` (default when `OPENAI_API_KEY` is set), `local` or `off`, the
+    /// patterns in `MODERATION_PATTERNS` are checked unless moderation is off
+    pub fn from_env(http: Arc<HttpClient>) -> Moderator {
+        let api_key = env::var("OPENAI_API_KEY").ok();
+        let mode = env::var("MODERATION").unwrap_or_else(|_| match api_key {
+            Some(_) => "openai".to_string(),
+            None => "local".to_string(),
+        });
+
+        if mode == "off" {
+            return Moderator {
+                patterns: Vec::new(),
+                openai: None,
+            };
+        }
+
+        let patterns = match env::var("MODERATION_PATTERNS") {
+            Ok(path) => read_patterns(&path),
+            Err(_) => Vec::new(),
+        };
+        let openai = match mode.as_str() {
+            "openai" => Some((
+                http,
+                api_key.expect("Expected OPENAI_API_KEY for OpenAI moderation"),
+            )),
+            _ => None,
+        };
+
+        Moderator { patterns, openai }
+    }
+
+    /// False when moderation is off, so nothing is ever flagged
+    pub fn is_active(&self) -> bool {
+        !self.patterns.is_empty() || self.openai.is_some()
+    }
+
+    /// Returns why the text was flagged, `None` when it is fine
+    pub async fn check(&self, text: &str) -> Result<Option<String>, LlmError> {
+        if let Some(pattern) = self.patterns.iter().find(|pattern| pattern.is_match(text)) {
+            return Ok(Some(format!("matched `{}`", pattern.as_str())));
+        }
+
+        let (http, api_key) = match &self.openai {
+            Some(openai) => openai,
+            None => return Ok(None),
+        };
+
+        let request = http
+            .client()
+            .post(MODERATIONS_URL)
+            .bearer_auth(api_key)
+            .json(&json!({ "input": text }));
+        let v: Value = http.send(request).await?.json().await?;
+
+        let result = &v["results"][0];
+        match result["flagged"].as_bool() {
+            Some(false) => Ok(None),
+            Some(true) => {
+                let categories: Vec<&str> = result["categories"]
+                    .as_object()
+                    .map(|categories| {
+                        categories
+                            .iter()
+                            .filter(|(_, flagged)| flagged.as_bool().unwrap_or(false))
+                            .map(|(category, _)| category.as_str())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Ok(Some(categories.join(", ")))
+            }
+            None => Err(LlmError::Response(v["error"]["message"].to_string())),
+        }
+    }
+}
+
+/// One case insensitive regex per line, empty lines and lines starting with `#` are skipped
+fn read_patterns(path: &str) -> Vec<Regex> {
+    let text = fs::read_to_string(path).expect("Failed to read moderation patterns");
+
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            RegexBuilder::new(line)
+                .case_insensitive(true)
+                .build()
+                .expect("Failed to parse moderation pattern")
+        })
+        .collect()
+}
+
+/// Returns the moderator that was inserted into `Context::data` on startup
+pub async fn get(ctx: &Context) -> Arc<Moderator> {
+    ctx.data
+        .read()
+        .await
+        .get::<Moderation>()
+        .expect("Expected Moderation in TypeMap")
+        .clone()
+}
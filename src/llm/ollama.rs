@@ -0,0 +1,323 @@
+use crate::http::HttpClient;
+use crate::llm::{
+    ChatMessage, Completion, CompletionOptions, CompletionStream, FunctionCall, LineReader,
+    LlmBackend, LlmError, Tool, ToolCall, Usage, TIMEOUT,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::RequestBuilder;
+use serde_json::{json, Value};
+use serenity::async_trait;
+use std::sync::Arc;
+
+const OLLAMA_URL: &str = "http://localhost:11434";
+
+/// Talks to Ollama's native chat API
+pub struct OllamaBackend {
+    http: Arc<HttpClient>,
+    base_url: String,
+}
+
+impl OllamaBackend {
+    pub fn new(http: Arc<HttpClient>, base_url: Option<String>) -> OllamaBackend {
+        OllamaBackend {
+            http,
+            base_url: base_url
+                .unwrap_or_else(|| OLLAMA_URL.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+        }
+    }
+
+    /// Ollama only takes images inline, so they are downloaded and encoded first
+    async fn encode_images(&self, message: &ChatMessage) -> Result<Vec<String>, LlmError> {
+        let mut images = Vec::new();
+        for url in &message.images {
+            let image = self
+                .http
+                .get(url)
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            images.push(STANDARD.encode(image));
+        }
+        Ok(images)
+    }
+
+    async fn build_request(
+        &self,
+        messages: &[ChatMessage],
+        options: &CompletionOptions,
+        tools: &[Tool],
+        stream: bool,
+    ) -> Result<RequestBuilder, LlmError> {
+        let mut converted: Vec<Value> = Vec::new();
+        for message in options.with_system_prompt(messages) {
+            let images = self.encode_images(&message).await?;
+            converted.push(to_ollama_message(&message, images));
+        }
+        let mut body = json!({
+            "model": options.model,
+            "stream": stream,
+            "messages": converted,
+            "options": {
+                "temperature": options.temperature,
+                "num_predict": options.max_tokens
+            }
+        });
+        if !tools.is_empty() {
+            body["tools"] = tools.iter().map(Tool::to_json).collect();
+        }
+
+        Ok(self
+            .http
+            .client()
+            .post(format!("{}/api/chat", self.base_url))
+            .timeout(TIMEOUT)
+            .json(&body))
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OllamaBackend {
+    async fn complete(
+        &self,
+        messages: &[ChatMessage],
+        options: &CompletionOptions,
+        tools: &[Tool],
+    ) -> Result<Completion, LlmError> {
+        let request = self.build_request(messages, options, tools, false).await?;
+        let v: Value = self.http.send(request).await?.json().await?;
+
+        let text = match v["message"]["content"].as_str() {
+            Some(text) => text.trim().to_string(),
+            None => return Err(LlmError::Response(v["error"].to_string())),
+        };
+        Ok(Completion {
+            text,
+            tool_calls: parse_tool_calls(&v),
+            usage: parse_usage(&v),
+        })
+    }
+
+    async fn stream(
+        &self,
+        messages: &[ChatMessage],
+        options: &CompletionOptions,
+        tools: &[Tool],
+    ) -> Result<Box<dyn CompletionStream>, LlmError> {
+        let request = self.build_request(messages, options, tools, true).await?;
+        let response = self.http.send(request).await?.error_for_status()?;
+
+        Ok(Box::new(NdjsonStream {
+            lines: LineReader::new(response),
+            done: false,
+            usage: None,
+            tool_calls: Vec::new(),
+        }))
+    }
+}
+
+fn parse_usage(v: &Value) -> Option<Usage> {
+    Some(Usage {
+        prompt_tokens: v["prompt_eval_count"].as_u64()?,
+        completion_tokens: v["eval_count"].as_u64().unwrap_or_default(),
+    })
+}
+
+/// Ollama takes tool call arguments as an object where OpenAI uses a JSON string, and images as
+/// base64 next to the content
+fn to_ollama_message(message: &ChatMessage, images: Vec<String>) -> Value {
+    let mut v = json!({ "role": message.role, "content": message.content });
+    if !images.is_empty() {
+        v["images"] = json!(images);
+    }
+    if !message.tool_calls.is_empty() {
+        v["tool_calls"] = message
+            .tool_calls
+            .iter()
+            .map(|call| {
+                let arguments: Value =
+                    serde_json::from_str(&call.function.arguments).unwrap_or_default();
+                json!({ "function": { "name": call.function.name, "arguments": arguments } })
+            })
+            .collect();
+    }
+    v
+}
+
+/// Ollama doesn't give its tool calls ids, so they are numbered instead
+fn parse_tool_calls(v: &Value) -> Vec<ToolCall> {
+    let calls = match v["message"]["tool_calls"].as_array() {
+        Some(calls) => calls,
+        None => return Vec::new(),
+    };
+
+    calls
+        .iter()
+        .enumerate()
+        .map(|(i, call)| ToolCall {
+            id: format!("call_{}", i),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: call["function"]["name"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                arguments: call["function"]["arguments"].to_string(),
+            },
+        })
+        .collect()
+}
+
+/// Parses Ollama's stream, one JSON object per line
+struct NdjsonStream {
+    lines: LineReader,
+    done: bool,
+    usage: Option<Usage>,
+    tool_calls: Vec<ToolCall>,
+}
+
+#[async_trait]
+impl CompletionStream for NdjsonStream {
+    async fn next(&mut self) -> Option<Result<String, LlmError>> {
+        while !self.done {
+            let line = match self.lines.next_line().await {
+                Some(Ok(line)) => line,
+                Some(Err(why)) => {
+                    self.done = true;
+                    return Some(Err(why.into()));
+                }
+                None => break,
+            };
+
+            let v: Value = match serde_json::from_str(&line) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            // The last line carries the token counts
+            if v["done"].as_bool().unwrap_or(false) {
+                self.done = true;
+                self.usage = parse_usage(&v);
+            }
+            // Tool calls come whole rather than in pieces
+            self.tool_calls.extend(parse_tool_calls(&v));
+            match v["message"]["content"].as_str() {
+                Some(content) if !content.is_empty() => return Some(Ok(content.to_string())),
+                _ => continue,
+            }
+        }
+
+        None
+    }
+
+    fn usage(&self) -> Option<Usage> {
+        self.usage
+    }
+
+    fn tool_calls(&self) -> Vec<ToolCall> {
+        self.tool_calls.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::test_server;
+
+    fn options() -> CompletionOptions {
+        CompletionOptions {
+            model: "llama3".to_string(),
+            temperature: 0.5,
+            max_tokens: 100,
+            system_prompt: Some("Be brief".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn builds_chat_request() {
+        // Images are downloaded and sent inline, "png" encodes to "cG5n"
+        let image_url = test_server::serve("image/png", "png".to_string(), Vec::new());
+        let backend = OllamaBackend::new(
+            Arc::new(HttpClient::new()),
+            Some("http://localhost:11434/".to_string()),
+        );
+        let tool_call = ToolCall {
+            id: "call_0".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: "calendar".to_string(),
+                arguments: r#"{"round":1}"#.to_string(),
+            },
+        };
+        let messages = vec![
+            ChatMessage::user_with_images("What is this?".to_string(), vec![image_url]),
+            ChatMessage::tool_calls(String::new(), vec![tool_call]),
+        ];
+        let tools = vec![Tool {
+            name: "calendar",
+            description: "The season's races",
+            parameters: json!({ "type": "object", "properties": {} }),
+        }];
+
+        let request = backend
+            .build_request(&messages, &options(), &tools, true)
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.url().as_str(), "http://localhost:11434/api/chat");
+
+        let v: Value = serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(v["model"], "llama3");
+        assert_eq!(v["stream"], true);
+        assert_eq!(
+            v["options"],
+            json!({ "temperature": 0.5, "num_predict": 100 })
+        );
+        assert_eq!(
+            v["messages"][0],
+            json!({ "role": "system", "content": "Be brief" })
+        );
+        assert_eq!(v["messages"][1]["images"], json!(["cG5n"]));
+        assert_eq!(
+            v["messages"][2]["tool_calls"][0]["function"],
+            json!({ "name": "calendar", "arguments": { "round": 1 } })
+        );
+        assert_eq!(v["tools"][0]["function"]["name"], "calendar");
+    }
+
+    #[tokio::test]
+    async fn reads_streamed_chat() {
+        let body = [
+            r#"{"message":{"role":"assistant","content":"Hel"},"done":false}"#,
+            r#"{"message":{"role":"assistant","content":"lo wörld"},"done":false}"#,
+            r#"{"message":{"role":"assistant","content":"","tool_calls":[{"function":{"name":"calendar","arguments":{"round":1}}}]},"done":false}"#,
+            r#"{"message":{"role":"assistant","content":""},"done":true,"prompt_eval_count":7,"eval_count":3}"#,
+        ]
+        .join("\n");
+        let splits = vec![20, body.find('ö').unwrap() + 1];
+        let url = test_server::serve("application/x-ndjson", body, splits);
+
+        let backend = OllamaBackend::new(Arc::new(HttpClient::new()), Some(url));
+        let messages = vec![ChatMessage::user("Hi".to_string())];
+        let mut stream = backend.stream(&messages, &options(), &[]).await.unwrap();
+
+        let mut text = String::new();
+        while let Some(piece) = stream.next().await {
+            text.push_str(&piece.unwrap());
+        }
+        assert_eq!(text, "Hello wörld");
+
+        let usage = stream.usage().unwrap();
+        assert_eq!(usage.prompt_tokens, 7);
+        assert_eq!(usage.completion_tokens, 3);
+
+        let tool_calls = stream.tool_calls();
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id, "call_0");
+        assert_eq!(tool_calls[0].function.name, "calendar");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"round":1}"#);
+    }
+}
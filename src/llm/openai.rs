@@ -0,0 +1,458 @@
+use crate::http::HttpClient;
+use crate::llm::{
+    ChatMessage, Completion, CompletionOptions, CompletionStream, FunctionCall, LineReader,
+    LlmBackend, LlmError, Tool, ToolCall, Usage, TIMEOUT,
+};
+use reqwest::RequestBuilder;
+use serde_json::{json, Value};
+use serenity::async_trait;
+use std::env;
+use std::sync::Arc;
+
+const OPENAI_URL: &str = "https://api.openai.com/v1";
+/// Used for `/ai image` unless `LLM_IMAGE_MODEL` names another one
+const IMAGE_MODEL: &str = "dall-e-2";
+
+/// Talks to OpenAI or any server implementing its chat completions API, such as llama.cpp's
+/// server, vLLM or Ollama's `/v1` endpoints
+pub struct OpenAiBackend {
+    http: Arc<HttpClient>,
+    base_url: String,
+    api_key: Option<String>,
+    /// Whether to ask for usage at the end of streams, which some compatible servers reject
+    stream_usage: bool,
+}
+
+impl OpenAiBackend {
+    pub fn openai(http: Arc<HttpClient>, api_key: String) -> OpenAiBackend {
+        OpenAiBackend {
+            stream_usage: true,
+            ..OpenAiBackend::compatible(http, OPENAI_URL.to_string(), Some(api_key))
+        }
+    }
+
+    /// Local servers usually don't need a key
+    pub fn compatible(
+        http: Arc<HttpClient>,
+        base_url: String,
+        api_key: Option<String>,
+    ) -> OpenAiBackend {
+        OpenAiBackend {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            stream_usage: false,
+        }
+    }
+
+    fn build_request(
+        &self,
+        messages: &[ChatMessage],
+        options: &CompletionOptions,
+        tools: &[Tool],
+        stream: bool,
+    ) -> RequestBuilder {
+        let mut body = json!({
+            "model": options.model,
+            "temperature": options.temperature,
+            "max_tokens": options.max_tokens,
+            "top_p": 1.0,
+            "frequency_penalty": 0.2,
+            "presence_penalty": 0.35,
+            "stream": stream,
+            "messages": options
+                .with_system_prompt(messages)
+                .iter()
+                .map(to_openai_message)
+                .collect::<Vec<Value>>()
+        });
+
+        // Some compatible servers reject an empty list of tools
+        if !tools.is_empty() {
+            body["tools"] = tools.iter().map(Tool::to_json).collect();
+        }
+        // Streamed completions only report usage when asked to, in a final chunk without choices
+        if stream && self.stream_usage {
+            body["stream_options"] = json!({ "include_usage": true });
+        }
+
+        let request = self
+            .http
+            .client()
+            .post(format!("{}/chat/completions", self.base_url))
+            .timeout(TIMEOUT)
+            .json(&body);
+
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OpenAiBackend {
+    async fn complete(
+        &self,
+        messages: &[ChatMessage],
+        options: &CompletionOptions,
+        tools: &[Tool],
+    ) -> Result<Completion, LlmError> {
+        let request = self.build_request(messages, options, tools, false);
+        let v: Value = self.http.send(request).await?.json().await?;
+
+        let message = &v["choices"][0]["message"];
+        if message.is_null() {
+            return Err(LlmError::Response(v["error"]["message"].to_string()));
+        }
+
+        // The content is null when the assistant only asks for tools
+        Ok(Completion {
+            text: message["content"]
+                .as_str()
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+            tool_calls: serde_json::from_value(message["tool_calls"].clone()).unwrap_or_default(),
+            usage: parse_usage(&v),
+        })
+    }
+
+    async fn stream(
+        &self,
+        messages: &[ChatMessage],
+        options: &CompletionOptions,
+        tools: &[Tool],
+    ) -> Result<Box<dyn CompletionStream>, LlmError> {
+        let request = self.build_request(messages, options, tools, true);
+        let response = self.http.send(request).await?.error_for_status()?;
+
+        Ok(Box::new(SseStream {
+            lines: LineReader::new(response),
+            done: false,
+            usage: None,
+            tool_calls: Vec::new(),
+        }))
+    }
+
+    async fn generate_image(&self, prompt: &str, size: &str) -> Result<Vec<u8>, LlmError> {
+        let body = json!({
+            "model": env::var("LLM_IMAGE_MODEL").unwrap_or_else(|_| IMAGE_MODEL.to_string()),
+            "prompt": prompt,
+            "n": 1,
+            "size": size,
+            "response_format": "url"
+        });
+
+        let mut request = self
+            .http
+            .client()
+            .post(format!("{}/images/generations", self.base_url))
+            .timeout(TIMEOUT)
+            .json(&body);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let v: Value = self.http.send(request).await?.json().await?;
+
+        let url = match v["data"][0]["url"].as_str() {
+            Some(url) => url,
+            None => return Err(LlmError::Response(v["error"]["message"].to_string())),
+        };
+        let image = self
+            .http
+            .get(url)
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(image.to_vec())
+    }
+}
+
+/// Messages with images need their content split into a list of text and image parts
+fn to_openai_message(message: &ChatMessage) -> Value {
+    let mut v = serde_json::to_value(message).unwrap_or_default();
+    if message.images.is_empty() {
+        return v;
+    }
+
+    let mut parts = vec![json!({ "type": "text", "text": message.content })];
+    parts.extend(
+        message
+            .images
+            .iter()
+            .map(|url| json!({ "type": "image_url", "image_url": { "url": url } })),
+    );
+    v["content"] = Value::Array(parts);
+    if let Some(v) = v.as_object_mut() {
+        v.remove("images");
+    }
+    v
+}
+
+fn parse_usage(v: &Value) -> Option<Usage> {
+    Some(Usage {
+        prompt_tokens: v["usage"]["prompt_tokens"].as_u64()?,
+        completion_tokens: v["usage"]["completion_tokens"].as_u64().unwrap_or_default(),
+    })
+}
+
+/// Parses the server-sent events of a streamed chat completion
+struct SseStream {
+    lines: LineReader,
+    done: bool,
+    usage: Option<Usage>,
+    tool_calls: Vec<ToolCall>,
+}
+
+impl SseStream {
+    /// Tool calls arrive in pieces, the first carrying the id and name and the rest adding to the
+    /// arguments
+    fn add_tool_call_deltas(&mut self, deltas: &[Value]) {
+        for delta in deltas {
+            let index = delta["index"].as_u64().unwrap_or_default() as usize;
+            while self.tool_calls.len() <= index {
+                self.tool_calls.push(ToolCall {
+                    id: String::new(),
+                    kind: "function".to_string(),
+                    function: FunctionCall::default(),
+                });
+            }
+
+            let call = &mut self.tool_calls[index];
+            if let Some(id) = delta["id"].as_str() {
+                call.id.push_str(id);
+            }
+            if let Some(name) = delta["function"]["name"].as_str() {
+                call.function.name.push_str(name);
+            }
+            if let Some(arguments) = delta["function"]["arguments"].as_str() {
+                call.function.arguments.push_str(arguments);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CompletionStream for SseStream {
+    async fn next(&mut self) -> Option<Result<String, LlmError>> {
+        while !self.done {
+            let line = match self.lines.next_line().await {
+                Some(Ok(line)) => line,
+                Some(Err(why)) => {
+                    self.done = true;
+                    return Some(Err(why.into()));
+                }
+                None => break,
+            };
+            let data = match line.strip_prefix("data:") {
+                Some(data) => data.trim(),
+                None => continue,
+            };
+
+            if data == "[DONE]" {
+                self.done = true;
+                break;
+            }
+
+            let v: Value = match serde_json::from_str(data) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            if let Some(usage) = parse_usage(&v) {
+                self.usage = Some(usage);
+            }
+            if let Some(deltas) = v["choices"][0]["delta"]["tool_calls"].as_array() {
+                self.add_tool_call_deltas(deltas);
+            }
+            match v["choices"][0]["delta"]["content"].as_str() {
+                Some(content) if !content.is_empty() => return Some(Ok(content.to_string())),
+                _ => continue,
+            }
+        }
+
+        None
+    }
+
+    fn usage(&self) -> Option<Usage> {
+        self.usage
+    }
+
+    fn tool_calls(&self) -> Vec<ToolCall> {
+        self.tool_calls.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::test_server;
+
+    fn options() -> CompletionOptions {
+        CompletionOptions {
+            model: "gpt-test".to_string(),
+            temperature: 0.5,
+            max_tokens: 100,
+            system_prompt: None,
+        }
+    }
+
+    fn weather_tool() -> Tool {
+        Tool {
+            name: "weather",
+            description: "The weather at the track",
+            parameters: json!({ "type": "object", "properties": {} }),
+        }
+    }
+
+    fn body(request: &reqwest::Request) -> Value {
+        serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn builds_chat_completions_request() {
+        let backend = OpenAiBackend::openai(Arc::new(HttpClient::new()), "sk-test".to_string());
+        let tool_call = ToolCall {
+            id: "call_1".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: "weather".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        let messages = vec![
+            ChatMessage::user_with_images(
+                "What is this?".to_string(),
+                vec!["https://example.com/car.png".to_string()],
+            ),
+            ChatMessage::tool_calls(String::new(), vec![tool_call]),
+            ChatMessage::tool_result("call_1".to_string(), "Sunny".to_string()),
+        ];
+        let options = CompletionOptions {
+            system_prompt: Some("Be brief".to_string()),
+            ..options()
+        };
+
+        let request = backend
+            .build_request(&messages, &options, &[weather_tool()], false)
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://api.openai.com/v1/chat/completions"
+        );
+        assert_eq!(
+            request.headers()["authorization"].to_str().unwrap(),
+            "Bearer sk-test"
+        );
+
+        let v = body(&request);
+        assert_eq!(v["model"], "gpt-test");
+        assert_eq!(v["max_tokens"], 100);
+        assert_eq!(v["stream"], false);
+        assert_eq!(
+            v["messages"][0],
+            json!({ "role": "system", "content": "Be brief" })
+        );
+        assert_eq!(
+            v["messages"][1]["content"],
+            json!([
+                { "type": "text", "text": "What is this?" },
+                { "type": "image_url", "image_url": { "url": "https://example.com/car.png" } }
+            ])
+        );
+        assert!(v["messages"][1].get("images").is_none());
+        assert_eq!(
+            v["messages"][2]["tool_calls"][0]["function"]["name"],
+            "weather"
+        );
+        assert_eq!(v["messages"][3]["role"], "tool");
+        assert_eq!(v["messages"][3]["tool_call_id"], "call_1");
+        assert_eq!(v["tools"][0]["function"]["name"], "weather");
+    }
+
+    #[test]
+    fn leaves_out_empty_tools() {
+        let backend = OpenAiBackend::compatible(
+            Arc::new(HttpClient::new()),
+            "http://localhost:8080/v1/".to_string(),
+            None,
+        );
+        let messages = vec![ChatMessage::user("Hi".to_string())];
+
+        let request = backend
+            .build_request(&messages, &options(), &[], true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "http://localhost:8080/v1/chat/completions"
+        );
+        assert!(request.headers().get("authorization").is_none());
+
+        let v = body(&request);
+        assert!(v.get("tools").is_none());
+        assert_eq!(v["stream"], true);
+        assert!(v.get("stream_options").is_none());
+    }
+
+    #[test]
+    fn asks_openai_for_stream_usage() {
+        let backend = OpenAiBackend::openai(Arc::new(HttpClient::new()), "sk-test".to_string());
+        let messages = vec![ChatMessage::user("Hi".to_string())];
+
+        let streamed = backend.build_request(&messages, &options(), &[], true);
+        let v = body(&streamed.build().unwrap());
+        assert_eq!(v["stream_options"]["include_usage"], true);
+
+        let completed = backend.build_request(&messages, &options(), &[], false);
+        assert!(body(&completed.build().unwrap())
+            .get("stream_options")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_streamed_completion() {
+        let body = [
+            ": keep-alive",
+            r#"data: {"choices":[{"delta":{"content":"Hel"}}]}"#,
+            r#"data: {"choices":[{"delta":{"content":"lo wörld"}}]}"#,
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"id":"call_1","function":{"name":"calendar","arguments":""}}]}}]}"#,
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"round\":"}}]}}]}"#,
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"1}"}}]}}]}"#,
+            r#"data: {"choices":[],"usage":{"prompt_tokens":12,"completion_tokens":5}}"#,
+            "data: [DONE]",
+            r#"data: {"choices":[{"delta":{"content":"after done"}}]}"#,
+        ]
+        .join("\n\n");
+        // Split inside a line, inside the "ö" and right after a newline
+        let splits = vec![
+            30,
+            body.find('ö').unwrap() + 1,
+            body.find("data: {\"choices\":[]").unwrap(),
+        ];
+        let url = test_server::serve("text/event-stream", body, splits);
+
+        let backend = OpenAiBackend::compatible(Arc::new(HttpClient::new()), url, None);
+        let messages = vec![ChatMessage::user("Hi".to_string())];
+        let mut stream = backend.stream(&messages, &options(), &[]).await.unwrap();
+
+        let mut text = String::new();
+        while let Some(piece) = stream.next().await {
+            text.push_str(&piece.unwrap());
+        }
+        assert_eq!(text, "Hello wörld");
+
+        let usage = stream.usage().unwrap();
+        assert_eq!(usage.prompt_tokens, 12);
+        assert_eq!(usage.completion_tokens, 5);
+
+        let tool_calls = stream.tool_calls();
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id, "call_1");
+        assert_eq!(tool_calls[0].function.name, "calendar");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"round":1}"#);
+    }
+}
@@ -0,0 +1,67 @@
+//! A local HTTP server for testing how streamed responses are read
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+/// Answers a single request with `body`, written in pieces split at the given byte offsets so
+/// the client sees them as separate chunks. Returns the server's base URL.
+pub fn serve(content_type: &'static str, body: String, splits: Vec<usize>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind test server");
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("Failed to accept test request");
+        read_request(&mut stream);
+
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nConnection: close\r\n\r\n",
+            content_type
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+
+        // The body ends when the connection is closed
+        let body = body.into_bytes();
+        let mut start = 0;
+        for end in splits.into_iter().chain([body.len()]) {
+            stream.write_all(&body[start..end]).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+            start = end;
+        }
+    });
+
+    url
+}
+
+/// Reads the request's headers and body so closing the connection doesn't reset it
+fn read_request(stream: &mut impl Read) {
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+
+    loop {
+        let read = stream.read(&mut buffer).unwrap();
+        request.extend_from_slice(&buffer[..read]);
+
+        let text = String::from_utf8_lossy(&request);
+        if let Some(end) = text.find("\r\n\r\n") {
+            let length = text[..end]
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    match name.eq_ignore_ascii_case("content-length") {
+                        true => value.trim().parse::<usize>().ok(),
+                        false => None,
+                    }
+                })
+                .unwrap_or_default();
+            if request.len() >= end + 4 + length {
+                return;
+            }
+        }
+        if read == 0 {
+            return;
+        }
+    }
+}
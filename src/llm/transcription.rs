@@ -0,0 +1,76 @@
+use crate::http::HttpClient;
+use crate::llm::{LlmError, TIMEOUT};
+use reqwest::multipart::{Form, Part};
+use serde_json::Value;
+use serenity::client::Context;
+use serenity::prelude::TypeMapKey;
+use std::env;
+use std::sync::Arc;
+
+const TRANSCRIPTIONS_URL: &str = "https://api.openai.com/v1/audio/transcriptions";
+const TRANSCRIPTION_MODEL: &str = "whisper-1";
+
+/// Turns speech into text through a Whisper compatible `/v1/audio/transcriptions` endpoint
+pub struct Transcriber {
+    http: Arc<HttpClient>,
+    url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+/// Shared through `Context::data`
+pub struct Transcription;
+
+impl TypeMapKey for Transcription {
+    type Value = Arc<Transcriber>;
+}
+
+impl Transcriber {
+    /// Uses OpenAI unless `TRANSCRIPTION_URL` points at another server, such as a local whisper
+    /// server which usually doesn't need `TRANSCRIPTION_API_KEY`
+    pub fn from_env(http: Arc<HttpClient>) -> Transcriber {
+        Transcriber {
+            http,
+            url: env::var("TRANSCRIPTION_URL").unwrap_or_else(|_| TRANSCRIPTIONS_URL.to_string()),
+            api_key: env::var("TRANSCRIPTION_API_KEY")
+                .or_else(|_| env::var("OPENAI_API_KEY"))
+                .ok(),
+            model: env::var("TRANSCRIPTION_MODEL")
+                .unwrap_or_else(|_| TRANSCRIPTION_MODEL.to_string()),
+        }
+    }
+
+    /// Returns the text spoken in the audio file, the filename tells the server its format
+    pub async fn transcribe(&self, audio: Vec<u8>, filename: &str) -> Result<String, LlmError> {
+        let form = Form::new()
+            .text("model", self.model.clone())
+            .text("response_format", "json")
+            .part("file", Part::bytes(audio).file_name(filename.to_string()));
+
+        let mut request = self
+            .http
+            .client()
+            .post(&self.url)
+            .timeout(TIMEOUT)
+            .multipart(form);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let v: Value = self.http.send(request).await?.json().await?;
+
+        match v["text"].as_str() {
+            Some(text) => Ok(text.trim().to_string()),
+            None => Err(LlmError::Response(v["error"]["message"].to_string())),
+        }
+    }
+}
+
+/// Returns the transcriber that was inserted into `Context::data` on startup
+pub async fn get(ctx: &Context) -> Arc<Transcriber> {
+    ctx.data
+        .read()
+        .await
+        .get::<Transcription>()
+        .expect("Expected Transcription in TypeMap")
+        .clone()
+}
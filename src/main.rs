@@ -1,139 +1,85 @@
 mod commands;
+mod http;
+mod llm;
+mod openf1;
+mod store;
+mod tasks;
 
+use std::collections::HashMap;
 use std::env;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use serenity::async_trait;
-use serenity::model::application::command::{Command, CommandOptionType};
+use serenity::http::Http;
 use serenity::model::application::interaction::Interaction;
-use serenity::model::gateway::{Activity, Ready};
+use serenity::model::channel::{GuildChannel, Message, PartialGuildChannel};
+use serenity::model::gateway::Ready;
 use serenity::model::user::OnlineStatus;
 use serenity::prelude::*;
 
-struct Handler;
+struct Handler {
+    tasks_started: AtomicBool,
+    registry: commands::registry::Registry,
+}
 
 #[async_trait]
 impl EventHandler for Handler {
+    async fn message(&self, ctx: Context, msg: Message) {
+        commands::openai::chat_message(ctx, msg).await;
+    }
+
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
         if let Interaction::ApplicationCommand(command) = interaction {
-            match command.data.name.as_str() {
-                "ping" => commands::util::ping(ctx, command).await,
-                "ai" => {
-                    let option: &str = command
-                        .data
-                        .options
-                        .get(0)
-                        .expect("Expected user to select option")
-                        .name
-                        .as_ref();
-                    match option {
-                        "prompt" => commands::openai::text_prompt(ctx, command).await,
-                        _ => {
-                            commands::util::generate_message(
-                                ctx,
-                                command,
-                                "Invalid option".to_string(),
-                            )
-                            .await
-                        }
-                    }
-                }
-                "f1" => {
-                    let option: &str = command
-                        .data
-                        .options
-                        .get(0)
-                        .expect("Expected user to select option")
-                        .name
-                        .as_ref();
-                    match option {
-                        "constructors" => commands::f1::constructor_standings(ctx, command).await,
-                        "drivers" => commands::f1::driver_standings(ctx, command).await,
-                        "calendar" => commands::f1::season_calendar(ctx, command).await,
-                        "recent_race_results" => {
-                            commands::f1::recent_race_results(ctx, command).await
-                        }
-                        _ => {
-                            commands::util::generate_message(
-                                ctx,
-                                command,
-                                "Invalid option".to_string(),
-                            )
-                            .await
-                        }
-                    }
-                }
-                _ => {
-                    commands::util::generate_message(
-                        ctx,
-                        command,
-                        "Not implemented :(".to_string(),
-                    )
-                    .await;
-                }
-            };
+            self.registry.dispatch(ctx, command).await;
+        }
+    }
+
+    async fn thread_update(&self, ctx: Context, thread: GuildChannel) {
+        // Archived AI chat threads aren't answered anymore, so their history can go
+        if thread
+            .thread_metadata
+            .is_some_and(|metadata| metadata.archived)
+        {
+            commands::openai::end_conversation(&ctx, thread.id).await;
         }
     }
 
+    async fn thread_delete(&self, ctx: Context, thread: PartialGuildChannel) {
+        commands::openai::end_conversation(&ctx, thread.id).await;
+    }
+
     async fn ready(&self, ctx: Context, ready: Ready) {
         println!("{} is connected!", ready.user.name);
 
+        // Ready fires again on every reconnect, only start the background tasks once
+        if !self.tasks_started.swap(true, Ordering::SeqCst) {
+            tasks::start(ctx.clone());
+        }
+
         ctx.set_presence(
-            Some(Activity::playing("| Accepting slash commands!")),
+            Some(tasks::presence::default_activity()),
             OnlineStatus::Online,
         )
         .await;
 
-        // create global commands
-        let _ = Command::create_global_application_command(&ctx.http, |command| {
-            command
-                .name("ping")
-                .description("A ping command to verify if the bot is accepting comands")
-        })
-        .await;
+        // Ready fires again on every reconnect, syncing only touches commands that changed
+        self.registry.sync(&ctx.http).await;
+    }
+}
 
-        let _ = Command::create_global_application_command(&ctx.http, |command| {
-            command
-                .name("f1")
-                .description("Get the current F1 standings and calendar")
-                .create_option(|option| {
-                    option
-                        .name("constructors")
-                        .description("Get current constructor standings")
-                        .kind(CommandOptionType::SubCommand)
-                })
-                .create_option(|option| {
-                    option
-                        .name("drivers")
-                        .description("Get current driver standings")
-                        .kind(CommandOptionType::SubCommand)
-                })
-                .create_option(|option| {
-                    option
-                        .name("calendar")
-                        .description("Get the season's calendar")
-                        .kind(CommandOptionType::SubCommand)
-                })
-                .create_option(|option| {
-                    option
-                        .name("recent_race_results")
-                        .description("Get the results from the most recent Grand Prix")
-                        .kind(CommandOptionType::SubCommand)
-                })
-        })
-        .await;
+/// Syncs the commands without connecting to the gateway and exits, failing when any change failed
+async fn sync_only(token: &str) {
+    let http = Http::new(token);
+    let application = http
+        .get_current_application_info()
+        .await
+        .expect("Failed to fetch the application");
+    http.set_application_id(application.id.0);
 
-        let _ = Command::create_global_application_command(&ctx.http, |command| {
-            command
-                .name("ai")
-                .description("Interact with an Open AI model")
-                .create_option(|option| {
-                    option
-                        .name("prompt")
-                        .description("The text sent to OpenAI")
-                        .kind(CommandOptionType::String)
-                })
-        })
-        .await;
+    if !commands::registry::Registry::new().sync(&http).await {
+        process::exit(1);
     }
 }
 
@@ -143,12 +89,41 @@ async fn main() {
     dotenv::dotenv().expect("Failed to load .env file");
     let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
 
+    if env::args().any(|arg| arg == "--sync-only") {
+        sync_only(&token).await;
+        return;
+    }
+
     // Build client.
-    let mut client = Client::builder(token, GatewayIntents::empty())
-        .event_handler(Handler)
+    // Messages sent in AI chat threads need to be read to be answered, and thread events tell
+    // when those threads are archived or deleted
+    let intents =
+        GatewayIntents::GUILDS | GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+    let mut client = Client::builder(token, intents)
+        .event_handler(Handler {
+            tasks_started: AtomicBool::new(false),
+            registry: commands::registry::Registry::new(),
+        })
         .await
         .expect("Error creating client");
 
+    // Make persistent storage available to every command
+    {
+        let mut data = client.data.write().await;
+        data.insert::<store::Store>(Arc::new(store::Store::load()));
+        data.insert::<commands::live::LiveTrackers>(Arc::new(Mutex::new(HashMap::new())));
+        data.insert::<commands::openai::ConversationLocks>(Arc::new(Mutex::new(HashMap::new())));
+        let http = Arc::new(http::HttpClient::new());
+        data.insert::<llm::Llm>(llm::from_env(http.clone()));
+        data.insert::<llm::moderation::Moderation>(Arc::new(llm::moderation::Moderator::from_env(
+            http.clone(),
+        )));
+        data.insert::<llm::transcription::Transcription>(Arc::new(
+            llm::transcription::Transcriber::from_env(http.clone()),
+        ));
+        data.insert::<http::Http>(http);
+    }
+
     // Finally, start a single shard, and start listening to events.
     // Shards will automatically attempt to reconnect, and will perform
     // exponential backoff until it reconnects.
@@ -0,0 +1,243 @@
+use crate::http::{HttpClient, HttpError};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::time::Instant;
+
+const DEFAULT_URL: &str = "https://api.openf1.org/v1";
+
+/// Base URL of the live timing feed, `OPENF1_URL` lets a local replay server stand in for OpenF1
+pub fn base_url() -> String {
+    env::var("OPENF1_URL")
+        .unwrap_or_else(|_| DEFAULT_URL.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SessionInfo {
+    pub session_key: u32,
+    pub session_name: String,
+    pub session_type: String,
+    pub location: Option<String>,
+    pub date_end: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct Driver {
+    pub driver_number: u32,
+    pub name_acronym: String,
+}
+
+#[derive(Deserialize)]
+pub struct Position {
+    pub date: DateTime<Utc>,
+    pub driver_number: u32,
+    pub position: u32,
+}
+
+#[derive(Deserialize)]
+pub struct Lap {
+    pub date_start: Option<DateTime<Utc>>,
+    pub driver_number: u32,
+    pub lap_number: u32,
+}
+
+#[derive(Deserialize)]
+pub struct Interval {
+    pub date: DateTime<Utc>,
+    pub driver_number: u32,
+    /// Seconds behind the leader, or a string such as `+1 LAP`
+    pub gap_to_leader: Option<Value>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RaceControl {
+    pub date: DateTime<Utc>,
+    pub category: String,
+    pub flag: Option<String>,
+    pub message: String,
+    pub scope: Option<String>,
+    pub lap_number: Option<u32>,
+}
+
+/// Broader grouping of race control messages guilds can filter their feed by
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RaceControlCategory {
+    Flags,
+    SafetyCar,
+    Penalties,
+    Investigations,
+    TrackLimits,
+    Other,
+}
+
+impl RaceControlCategory {
+    pub const ALL: [RaceControlCategory; 6] = [
+        RaceControlCategory::Flags,
+        RaceControlCategory::SafetyCar,
+        RaceControlCategory::Penalties,
+        RaceControlCategory::Investigations,
+        RaceControlCategory::TrackLimits,
+        RaceControlCategory::Other,
+    ];
+
+    /// Name of the matching option on `/f1 race_control subscribe`
+    pub fn option_name(&self) -> &'static str {
+        match self {
+            RaceControlCategory::Flags => "flags",
+            RaceControlCategory::SafetyCar => "safety_car",
+            RaceControlCategory::Penalties => "penalties",
+            RaceControlCategory::Investigations => "investigations",
+            RaceControlCategory::TrackLimits => "track_limits",
+            RaceControlCategory::Other => "other",
+        }
+    }
+}
+
+impl RaceControl {
+    pub fn classify(&self) -> RaceControlCategory {
+        let text = self.message.to_uppercase();
+
+        match self.category.as_str() {
+            // Blue flags are shown to individual drivers constantly, they would drown everything else
+            "Flag" if self.flag.as_deref() == Some("BLUE") => RaceControlCategory::Other,
+            "Flag" => RaceControlCategory::Flags,
+            "SafetyCar" => RaceControlCategory::SafetyCar,
+            _ if text.contains("PENALTY") => RaceControlCategory::Penalties,
+            _ if text.contains("INVESTIGATION") || text.contains("NOTED") => {
+                RaceControlCategory::Investigations
+            }
+            _ if text.contains("TRACK LIMITS") || text.contains("DELETED") => {
+                RaceControlCategory::TrackLimits
+            }
+            _ => RaceControlCategory::Other,
+        }
+    }
+}
+
+/// Where race control messages are read from
+pub enum RaceControlSource {
+    OpenF1,
+    /// Plays back a recorded list of messages at their original pace, starting when the bot does
+    Replay {
+        messages: Vec<RaceControl>,
+        started: Instant,
+    },
+}
+
+impl RaceControlSource {
+    /// Uses the recording at `RACE_CONTROL_REPLAY` when set, the live feed otherwise
+    pub fn from_env() -> RaceControlSource {
+        match env::var("RACE_CONTROL_REPLAY") {
+            Ok(path) => {
+                let text = fs::read_to_string(path).expect("Failed to read race control replay");
+                let mut messages: Vec<RaceControl> =
+                    serde_json::from_str(&text).expect("Failed to parse race control replay");
+                messages.sort_by_key(|message| message.date);
+
+                RaceControlSource::Replay {
+                    messages,
+                    started: Instant::now(),
+                }
+            }
+            Err(_) => RaceControlSource::OpenF1,
+        }
+    }
+
+    /// Returns the messages published at or after `since`, more can share its timestamp
+    pub async fn fetch(
+        &self,
+        http: &HttpClient,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<RaceControl>, HttpError> {
+        match self {
+            RaceControlSource::OpenF1 => get_race_control(http, "latest", since).await,
+            RaceControlSource::Replay { messages, started } => {
+                let first = match messages.first() {
+                    Some(message) => message.date,
+                    None => return Ok(Vec::new()),
+                };
+                let elapsed = chrono::Duration::from_std(started.elapsed()).unwrap_or_default();
+
+                Ok(messages
+                    .iter()
+                    .filter(|message| message.date - first <= elapsed)
+                    .filter(|message| since.is_none_or(|since| message.date >= since))
+                    .cloned()
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Fetches an endpoint for a session, only returning entries from `since` on when given. Entries
+/// at `since` itself are included again since more can arrive with the same timestamp later.
+async fn fetch<T: DeserializeOwned>(
+    http: &HttpClient,
+    endpoint: &str,
+    session_key: &str,
+    date_field: &str,
+    since: Option<DateTime<Utc>>,
+) -> Result<Vec<T>, HttpError> {
+    let mut url = format!("{}/{}?session_key={}", base_url(), endpoint, session_key);
+    if let Some(since) = since {
+        url.push_str(&format!(
+            "&{}>={}",
+            date_field,
+            since.to_rfc3339_opts(SecondsFormat::Micros, true)
+        ));
+    }
+
+    Ok(http.get(&url).await?.json().await?)
+}
+
+/// Returns the session, `latest` being the one currently running or the most recent one
+pub async fn get_session(
+    http: &HttpClient,
+    session_key: &str,
+) -> Result<Option<SessionInfo>, HttpError> {
+    let sessions: Vec<SessionInfo> =
+        fetch(http, "sessions", session_key, "date_start", None).await?;
+    Ok(sessions.into_iter().last())
+}
+
+pub async fn get_drivers(http: &HttpClient, session_key: &str) -> Result<Vec<Driver>, HttpError> {
+    fetch(http, "drivers", session_key, "date", None).await
+}
+
+pub async fn get_positions(
+    http: &HttpClient,
+    session_key: &str,
+    since: Option<DateTime<Utc>>,
+) -> Result<Vec<Position>, HttpError> {
+    fetch(http, "position", session_key, "date", since).await
+}
+
+pub async fn get_laps(
+    http: &HttpClient,
+    session_key: &str,
+    since: Option<DateTime<Utc>>,
+) -> Result<Vec<Lap>, HttpError> {
+    fetch(http, "laps", session_key, "date_start", since).await
+}
+
+pub async fn get_intervals(
+    http: &HttpClient,
+    session_key: &str,
+    since: Option<DateTime<Utc>>,
+) -> Result<Vec<Interval>, HttpError> {
+    fetch(http, "intervals", session_key, "date", since).await
+}
+
+pub async fn get_race_control(
+    http: &HttpClient,
+    session_key: &str,
+    since: Option<DateTime<Utc>>,
+) -> Result<Vec<RaceControl>, HttpError> {
+    fetch(http, "race_control", session_key, "date", since).await
+}
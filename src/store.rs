@@ -0,0 +1,145 @@
+use crate::commands::moderation::ModerationSettings;
+use crate::commands::openai::AiSettings;
+use crate::commands::persona::Personas;
+use crate::commands::quota::{QuotaLimits, QuotaUsage};
+use crate::commands::usage::UsageRecord;
+use crate::llm::ChatMessage;
+use crate::openf1::RaceControlCategory;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serenity::client::Context;
+use serenity::model::id::{ChannelId, GuildId, ScheduledEventId, UserId};
+use serenity::prelude::{RwLock, TypeMapKey};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const DEFAULT_PATH: &str = "tenbot.json";
+
+/// Everything the bot needs to remember between restarts
+#[derive(Default, Serialize, Deserialize)]
+pub struct StoreData {
+    /// Scheduled events created by `/f1 sync_events`, keyed by season, round and session
+    #[serde(default)]
+    pub scheduled_events: HashMap<GuildId, HashMap<String, ScheduledEventId>>,
+    /// Channel each guild wants race weekend discussion threads opened in
+    #[serde(default)]
+    pub weekend_threads: HashMap<GuildId, ChannelId>,
+    /// Discussion threads that have been opened, keyed the same way as scheduled events
+    #[serde(default)]
+    pub session_threads: HashMap<GuildId, HashMap<String, SessionThread>>,
+    /// Race control feed subscriptions made with `/f1 race_control subscribe`
+    #[serde(default)]
+    pub race_control: HashMap<GuildId, RaceControlSubscription>,
+    /// History of the AI conversations started with `/ai chat`, keyed by their thread
+    #[serde(default)]
+    pub conversations: HashMap<ChannelId, Vec<ChatMessage>>,
+    /// AI settings changed with `/ai config`
+    #[serde(default)]
+    pub ai_settings: HashMap<GuildId, AiSettings>,
+    /// Cooldown and daily quotas changed with `/ai limits`
+    #[serde(default)]
+    pub ai_limits: HashMap<GuildId, QuotaLimits>,
+    /// Requests and tokens used today, counted against the quotas
+    #[serde(default)]
+    pub ai_usage: HashMap<GuildId, QuotaUsage>,
+    /// Tokens used per day, model and user over the last few months, reported on with `/ai usage`
+    #[serde(default)]
+    pub usage_records: HashMap<GuildId, Vec<UsageRecord>>,
+    /// The same for direct messages, kept per user
+    #[serde(default)]
+    pub direct_usage_records: HashMap<UserId, Vec<UsageRecord>>,
+    /// How flagged AI prompts and answers are handled, changed with `/ai moderation`
+    #[serde(default)]
+    pub ai_moderation: HashMap<GuildId, ModerationSettings>,
+    /// Named system prompts made with `/ai persona`
+    #[serde(default)]
+    pub ai_personas: HashMap<GuildId, Personas>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct SessionThread {
+    pub channel_id: ChannelId,
+    pub archived: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RaceControlSubscription {
+    pub channel_id: ChannelId,
+    pub categories: Vec<RaceControlCategory>,
+    /// Timestamp of the newest message already posted, used to skip duplicates
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Text of the messages already posted with the `last_seen` timestamp
+    #[serde(default)]
+    pub seen: Vec<String>,
+}
+
+/// JSON file backed storage shared through `Context::data`
+pub struct Store {
+    path: PathBuf,
+    data: RwLock<StoreData>,
+}
+
+impl TypeMapKey for Store {
+    type Value = Arc<Store>;
+}
+
+impl Store {
+    /// Loads the store from `DATA_FILE`, starting empty when the file does not exist yet
+    pub fn load() -> Store {
+        let path =
+            PathBuf::from(env::var("DATA_FILE").unwrap_or_else(|_| DEFAULT_PATH.to_string()));
+
+        let data = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).expect("Failed to parse data file"),
+            Err(_) => StoreData::default(),
+        };
+
+        Store {
+            path,
+            data: RwLock::new(data),
+        }
+    }
+
+    /// Reads from the stored data without holding on to the lock
+    pub async fn read<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&StoreData) -> T,
+    {
+        f(&*self.data.read().await)
+    }
+
+    /// Applies `f` to the stored data and writes the result back to disk
+    pub async fn write<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut StoreData) -> T,
+    {
+        let mut data = self.data.write().await;
+        let result = f(&mut data);
+
+        // Write to a temporary file first so a crash never leaves a half written store behind
+        let tmp = self.path.with_extension("tmp");
+        let saved = serde_json::to_string_pretty(&*data)
+            .map_err(|why| why.to_string())
+            .and_then(|text| fs::write(&tmp, text).map_err(|why| why.to_string()))
+            .and_then(|_| fs::rename(&tmp, &self.path).map_err(|why| why.to_string()));
+
+        if let Err(why) = saved {
+            println!("Cannot save data file: {}", why);
+        }
+
+        result
+    }
+}
+
+/// Returns the store that was inserted into `Context::data` on startup
+pub async fn get(ctx: &Context) -> Arc<Store> {
+    ctx.data
+        .read()
+        .await
+        .get::<Store>()
+        .expect("Expected Store in TypeMap")
+        .clone()
+}
@@ -0,0 +1,12 @@
+pub mod presence;
+pub mod race_control;
+pub mod weekend_threads;
+
+use serenity::client::Context;
+
+/// Spawns the jobs that run in the background for as long as the bot is connected
+pub fn start(ctx: Context) {
+    tokio::spawn(presence::run(ctx.clone()));
+    tokio::spawn(race_control::run(ctx.clone()));
+    tokio::spawn(weekend_threads::run(ctx));
+}
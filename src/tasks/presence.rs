@@ -0,0 +1,89 @@
+use crate::commands::f1::{self, Race, SessionKind};
+use crate::http;
+use chrono::{DateTime, Duration, Utc};
+use serenity::client::Context;
+use serenity::model::gateway::Activity;
+use serenity::model::user::OnlineStatus;
+use std::time;
+
+/// How often the countdown in the presence is refreshed
+const UPDATE_INTERVAL: time::Duration = time::Duration::from_secs(60);
+/// How often the calendar is fetched again, it rarely changes during a season
+const CALENDAR_REFRESH_MINUTES: i64 = 60;
+
+/// Shown whenever there is no calendar data to count down with
+pub fn default_activity() -> Activity {
+    Activity::playing("| Accepting slash commands!")
+}
+
+/// Keeps the bot's presence counting down to the next race
+pub async fn run(ctx: Context) {
+    let http = http::get(&ctx).await;
+    let mut interval = tokio::time::interval(UPDATE_INTERVAL);
+    let mut races: Vec<Race> = Vec::new();
+    let mut fetched_at: Option<DateTime<Utc>> = None;
+
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+
+        let stale = fetched_at
+            .is_none_or(|fetched| now - fetched >= Duration::minutes(CALENDAR_REFRESH_MINUTES));
+        if stale {
+            match f1::get_races(&http).await {
+                Ok(calendar) => {
+                    races = calendar;
+                    fetched_at = Some(now);
+                }
+                Err(why) => println!("Cannot fetch calendar for presence: {}", why),
+            }
+        }
+
+        let activity = race_activity(&races, now).unwrap_or_else(default_activity);
+        ctx.set_presence(Some(activity), OnlineStatus::Online).await;
+    }
+}
+
+/// Describes the live session or the time left until the next race
+fn race_activity(races: &[Race], now: DateTime<Utc>) -> Option<Activity> {
+    let live = races
+        .iter()
+        .flat_map(|race| race.sessions.iter())
+        .find(|session| session.start <= now && now < session.end());
+
+    if let Some(session) = live {
+        let name = match session.kind {
+            SessionKind::Race => "the race".to_string(),
+            kind => kind.name().to_string(),
+        };
+        return Some(Activity::watching(format!("{} — LIVE", name)));
+    }
+
+    let (race, start) = races.iter().find_map(|race| {
+        race.sessions
+            .iter()
+            .find(|session| session.kind == SessionKind::Race && session.start > now)
+            .map(|session| (race, session.start))
+    })?;
+
+    Some(Activity::watching(format!(
+        "{} in {}",
+        race.short_name(),
+        format_countdown(start - now)
+    )))
+}
+
+/// Formats the time left as e.g. `2d 4h`, `4h 30m` or `12m`
+fn format_countdown(left: Duration) -> String {
+    let days = left.num_days();
+    let hours = left.num_hours() % 24;
+    let minutes = left.num_minutes() % 60;
+
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes.max(1))
+    }
+}
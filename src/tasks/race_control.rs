@@ -0,0 +1,221 @@
+use crate::http;
+use crate::openf1::{RaceControl, RaceControlCategory, RaceControlSource};
+use crate::store::{self, RaceControlSubscription};
+use serenity::client::Context;
+use serenity::model::id::GuildId;
+use std::time;
+
+/// How often the race control source is polled while any guild is subscribed
+const POLL_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+/// Posts new race control messages to every subscribed guild
+pub async fn run(ctx: Context) {
+    let store = store::get(&ctx).await;
+    let http = http::get(&ctx).await;
+    let source = RaceControlSource::from_env();
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let subscriptions = store.read(|data| data.race_control.clone()).await;
+        if subscriptions.is_empty() {
+            continue;
+        }
+
+        // Only ask for what the guild that is furthest behind hasn't seen yet
+        let since = subscriptions
+            .values()
+            .map(|subscription| subscription.last_seen)
+            .min()
+            .flatten();
+
+        let messages = match source.fetch(&http, since).await {
+            Ok(messages) => messages,
+            Err(why) => {
+                println!("Cannot fetch race control messages: {}", why);
+                continue;
+            }
+        };
+        if messages.is_empty() {
+            continue;
+        }
+
+        for (guild_id, subscription) in subscriptions {
+            post_messages(&ctx, guild_id, &subscription, &messages).await;
+
+            store
+                .write(|data| {
+                    if let Some(subscription) = data.race_control.get_mut(&guild_id) {
+                        mark_seen(subscription, &messages);
+                    }
+                })
+                .await;
+        }
+    }
+}
+
+async fn post_messages(
+    ctx: &Context,
+    guild_id: GuildId,
+    subscription: &RaceControlSubscription,
+    messages: &[RaceControl],
+) {
+    for message in unseen(subscription, messages) {
+        if let Err(why) = subscription
+            .channel_id
+            .say(&ctx.http, format_message(message))
+            .await
+        {
+            println!("Cannot post race control message in {}: {}", guild_id, why);
+        }
+    }
+}
+
+/// The messages a subscription wants that weren't posted yet
+fn unseen<'a>(
+    subscription: &'a RaceControlSubscription,
+    messages: &'a [RaceControl],
+) -> impl Iterator<Item = &'a RaceControl> {
+    messages.iter().filter(move |message| {
+        // A fresh subscription starts from the current point in the session instead of its backlog
+        let new = match subscription.last_seen {
+            Some(since) if message.date == since => !subscription.seen.contains(&message.message),
+            Some(since) => message.date > since,
+            None => false,
+        };
+        new && subscription.categories.contains(&message.classify())
+    })
+}
+
+/// Remembers the newest messages so the next poll, which returns them again, skips them
+fn mark_seen(subscription: &mut RaceControlSubscription, messages: &[RaceControl]) {
+    let newest = match messages.iter().map(|message| message.date).max() {
+        Some(newest) => newest,
+        None => return,
+    };
+    if subscription.last_seen > Some(newest) {
+        return;
+    }
+    if subscription.last_seen < Some(newest) {
+        subscription.last_seen = Some(newest);
+        subscription.seen.clear();
+    }
+
+    for message in messages.iter().filter(|message| message.date == newest) {
+        if !subscription.seen.contains(&message.message) {
+            subscription.seen.push(message.message.clone());
+        }
+    }
+}
+
+fn format_message(message: &RaceControl) -> String {
+    let icon = match message.classify() {
+        RaceControlCategory::Flags => match message.flag.as_deref() {
+            Some("GREEN") | Some("CLEAR") => "🟢",
+            Some("YELLOW") | Some("DOUBLE YELLOW") => "🟡",
+            Some("RED") => "🔴",
+            Some("CHEQUERED") => "🏁",
+            _ => "🚩",
+        },
+        RaceControlCategory::SafetyCar => "🚨",
+        RaceControlCategory::Penalties => "⚖️",
+        RaceControlCategory::Investigations => "🔍",
+        RaceControlCategory::TrackLimits => "⏱️",
+        RaceControlCategory::Other => "ℹ️",
+    };
+
+    match message.lap_number {
+        Some(lap) => format!("`Lap {}` {} {}", lap, icon, message.message),
+        None => format!("{} {}", icon, message.message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HttpClient;
+    use serenity::model::id::ChannelId;
+    use std::time::Instant;
+
+    /// A recording of the feed as it was at some point, played back in full
+    fn replay(recording: &str) -> RaceControlSource {
+        let mut messages: Vec<RaceControl> = serde_json::from_str(recording).unwrap();
+        messages.sort_by_key(|message| message.date);
+
+        RaceControlSource::Replay {
+            messages,
+            started: Instant::now() - time::Duration::from_secs(60),
+        }
+    }
+
+    /// Does what a single iteration of `run` does for one guild, returning what it would post
+    async fn poll(
+        source: &RaceControlSource,
+        subscription: &mut RaceControlSubscription,
+    ) -> Vec<String> {
+        let messages = source
+            .fetch(&HttpClient::new(), subscription.last_seen)
+            .await
+            .unwrap();
+        let posted = unseen(subscription, &messages)
+            .map(|message| message.message.clone())
+            .collect();
+        mark_seen(subscription, &messages);
+        posted
+    }
+
+    #[tokio::test]
+    async fn posts_messages_sharing_a_timestamp_once() {
+        let a = r#"{"date": "2024-03-02T15:00:00Z", "category": "Flag", "flag": "GREEN", "message": "GREEN LIGHT - PIT EXIT OPEN", "scope": "Track", "lap_number": 1}"#;
+        let b = r#"{"date": "2024-03-02T15:00:05Z", "category": "Other", "flag": null, "message": "CAR 1 (VER) TIME 1:35.123 DELETED - TRACK LIMITS", "scope": null, "lap_number": 2}"#;
+        let c = r#"{"date": "2024-03-02T15:00:05Z", "category": "Other", "flag": null, "message": "CAR 16 (LEC) TIME 1:35.456 DELETED - TRACK LIMITS", "scope": null, "lap_number": 2}"#;
+        let d = r#"{"date": "2024-03-02T15:00:09Z", "category": "Other", "flag": null, "message": "FIA STEWARDS: TURN 4 INCIDENT NOTED", "scope": null, "lap_number": 3}"#;
+
+        let mut subscription = RaceControlSubscription {
+            channel_id: ChannelId(1),
+            categories: RaceControlCategory::ALL.to_vec(),
+            last_seen: None,
+            seen: Vec::new(),
+        };
+
+        // A fresh subscription skips the backlog
+        let posted = poll(&replay(&format!("[{}]", a)), &mut subscription).await;
+        assert!(posted.is_empty());
+
+        let posted = poll(&replay(&format!("[{}, {}]", a, b)), &mut subscription).await;
+        assert_eq!(
+            posted,
+            vec!["CAR 1 (VER) TIME 1:35.123 DELETED - TRACK LIMITS"]
+        );
+
+        // Arrives a poll later with the same timestamp as the one already posted
+        let posted = poll(
+            &replay(&format!("[{}, {}, {}]", a, b, c)),
+            &mut subscription,
+        )
+        .await;
+        assert_eq!(
+            posted,
+            vec!["CAR 16 (LEC) TIME 1:35.456 DELETED - TRACK LIMITS"]
+        );
+
+        let posted = poll(
+            &replay(&format!("[{}, {}, {}]", a, b, c)),
+            &mut subscription,
+        )
+        .await;
+        assert!(posted.is_empty());
+
+        let posted = poll(
+            &replay(&format!("[{}, {}, {}, {}]", a, b, c, d)),
+            &mut subscription,
+        )
+        .await;
+        assert_eq!(posted, vec!["FIA STEWARDS: TURN 4 INCIDENT NOTED"]);
+        assert_eq!(
+            subscription.seen,
+            vec!["FIA STEWARDS: TURN 4 INCIDENT NOTED"]
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use crate::commands::f1::{self, Race, Session};
+use crate::http;
+use crate::store::{self, SessionThread, Store};
+use chrono::{DateTime, Duration, Utc};
+use serde_json::json;
+use serenity::builder::CreateEmbed;
+use serenity::client::Context;
+use serenity::http::request::RequestBuilder;
+use serenity::http::routing::RouteInfo;
+use serenity::http::Http;
+use serenity::json::hashmap_to_json_map;
+use serenity::model::channel::{Channel, ChannelType, GuildChannel};
+use serenity::model::id::{ChannelId, GuildId, MessageId};
+use serenity::utils::{Colour, MessageBuilder};
+use std::collections::HashSet;
+use std::time;
+
+/// How often the calendar is checked for sessions that need a thread
+const POLL_INTERVAL: time::Duration = time::Duration::from_secs(5 * 60);
+/// How long before a session starts its thread is opened
+const LEAD_TIME_MINUTES: i64 = 30;
+/// How long after a session ends to start looking for its results
+const RESULTS_DELAY_MINUTES: i64 = 15;
+/// How long to keep waiting for Ergast to publish results before archiving without them
+const RESULTS_TIMEOUT_HOURS: i64 = 6;
+
+/// Opens a discussion thread before each session and archives it with the results afterwards
+pub async fn run(ctx: Context) {
+    let store = store::get(&ctx).await;
+    let http = http::get(&ctx).await;
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let channels = store.read(|data| data.weekend_threads.clone()).await;
+        // Threads opened before a guild turned them off still have to be closed
+        let guilds: HashSet<GuildId> = store
+            .read(|data| {
+                let with_open_threads = data
+                    .session_threads
+                    .iter()
+                    .filter(|(_, threads)| threads.values().any(|thread| !thread.archived))
+                    .map(|(guild_id, _)| guild_id);
+                data.weekend_threads
+                    .keys()
+                    .chain(with_open_threads)
+                    .copied()
+                    .collect()
+            })
+            .await;
+        if guilds.is_empty() {
+            continue;
+        }
+
+        let races = match f1::get_races(&http).await {
+            Ok(races) => races,
+            Err(why) => {
+                println!("Cannot fetch calendar for weekend threads: {}", why);
+                continue;
+            }
+        };
+        let now = Utc::now();
+
+        for race in &races {
+            for session in &race.sessions {
+                let key = race.session_key(session);
+
+                for guild_id in &guilds {
+                    let thread = store
+                        .read(|data| {
+                            data.session_threads
+                                .get(guild_id)
+                                .and_then(|threads| threads.get(&key))
+                                .copied()
+                        })
+                        .await;
+
+                    match (thread, channels.get(guild_id)) {
+                        (None, Some(channel_id))
+                            if now >= session.start - Duration::minutes(LEAD_TIME_MINUTES)
+                                && now < session.end() =>
+                        {
+                            open_thread(&ctx, &store, *guild_id, *channel_id, race, session).await
+                        }
+                        (Some(thread), _) if !thread.archived => {
+                            close_thread(&ctx, &store, *guild_id, thread, race, session, now).await
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Lists every session of the weekend, highlighting the one the thread is for
+fn schedule_embed(race: &Race, current: &Session) -> CreateEmbed {
+    let mut sessions = MessageBuilder::new();
+    let mut times = MessageBuilder::new();
+
+    for session in &race.sessions {
+        let name = if session.kind == current.kind {
+            format!("**{}**\n", session.kind.name())
+        } else {
+            format!("{}\n", session.kind.name())
+        };
+
+        sessions.push(name);
+        times.push(format!("<t:{}:F>\n", session.start.timestamp()));
+    }
+
+    let mut embed = CreateEmbed::default();
+    embed.title(format!("{} Weekend Schedule", race.display_name()));
+    embed.description(format!(
+        "Round {} of the {} season at {}, {}",
+        race.round, race.season, race.circuit, race.country
+    ));
+    embed.colour(Colour::DARK_RED);
+    embed.thumbnail("https://1000logos.net/wp-content/uploads/2020/02/F1-Logo-500x281.png");
+    embed.field("Session", sessions, true);
+    embed.field("Starts", times, true);
+
+    embed
+}
+
+/// Creates a thread in a text channel or a post in a forum channel and pins the schedule in it
+async fn open_thread(
+    ctx: &Context,
+    store: &Store,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    race: &Race,
+    session: &Session,
+) {
+    let name = format!("{} — {}", race.display_name(), session.kind.name());
+    let embed = schedule_embed(race, session);
+
+    let is_forum = matches!(
+        channel_id.to_channel(&ctx.http).await,
+        Ok(Channel::Guild(channel)) if channel.kind == ChannelType::Forum
+    );
+
+    let thread = if is_forum {
+        create_forum_post(&ctx.http, channel_id, &name, &embed).await
+    } else {
+        channel_id
+            .create_private_thread(&ctx.http, |thread| {
+                thread.name(&name).kind(ChannelType::PublicThread)
+            })
+            .await
+    };
+
+    let thread = match thread {
+        Ok(thread) => thread,
+        Err(why) => {
+            println!("Cannot open weekend thread: {}", why);
+            return;
+        }
+    };
+
+    // The starting message of a forum post shares its id with the post itself
+    let schedule = if is_forum {
+        Ok(MessageId(thread.id.0))
+    } else {
+        thread
+            .id
+            .send_message(&ctx.http, |message| message.set_embed(embed))
+            .await
+            .map(|message| message.id)
+    };
+
+    match schedule {
+        Ok(message_id) => {
+            if let Err(why) = thread.id.pin(&ctx.http, message_id).await {
+                println!("Cannot pin weekend schedule: {}", why);
+            }
+        }
+        Err(why) => println!("Cannot post weekend schedule: {}", why),
+    }
+
+    let key = race.session_key(session);
+    store
+        .write(|data| {
+            data.session_threads.entry(guild_id).or_default().insert(
+                key,
+                SessionThread {
+                    channel_id: thread.id,
+                    archived: false,
+                },
+            )
+        })
+        .await;
+}
+
+/// Starts a post in a forum channel, which Discord creates together with its first message.
+/// Serenity 0.11 has no method for these, so the request is sent to the channel's threads route.
+async fn create_forum_post(
+    http: &Http,
+    channel_id: ChannelId,
+    name: &str,
+    embed: &CreateEmbed,
+) -> serenity::Result<GuildChannel> {
+    let body = serde_json::to_vec(&json!({
+        "name": name,
+        "message": { "embeds": [hashmap_to_json_map(embed.0.clone())] },
+    }))?;
+
+    let mut request = RequestBuilder::new(RouteInfo::CreatePrivateThread {
+        channel_id: channel_id.0,
+    });
+    request.body(Some(&body));
+    http.fire(request.build()).await
+}
+
+/// Posts the session's results once they are available and archives the thread
+async fn close_thread(
+    ctx: &Context,
+    store: &Store,
+    guild_id: GuildId,
+    thread: SessionThread,
+    race: &Race,
+    session: &Session,
+    now: DateTime<Utc>,
+) {
+    if now < session.end() + Duration::minutes(RESULTS_DELAY_MINUTES) {
+        return;
+    }
+
+    let http = http::get(ctx).await;
+    let results = f1::get_session_results(&http, race, session.kind).await;
+    let gave_up = now >= session.end() + Duration::hours(RESULTS_TIMEOUT_HOURS);
+
+    // Practice sessions never have results, only wait on the ones that do
+    let message = match results {
+        Some(embed) => {
+            thread
+                .channel_id
+                .send_message(&ctx.http, |message| message.set_embed(embed))
+                .await
+        }
+        None if gave_up || !session.kind.has_results() => {
+            thread
+                .channel_id
+                .say(&ctx.http, format!("{} has finished!", session.kind.name()))
+                .await
+        }
+        None => return,
+    };
+
+    if let Err(why) = message {
+        println!("Cannot post session results: {}", why);
+    }
+
+    if let Err(why) = thread
+        .channel_id
+        .edit_thread(&ctx.http, |thread| thread.archived(true))
+        .await
+    {
+        println!("Cannot archive weekend thread: {}", why);
+    }
+
+    let key = race.session_key(session);
+    store
+        .write(|data| {
+            data.session_threads.entry(guild_id).or_default().insert(
+                key,
+                SessionThread {
+                    archived: true,
+                    ..thread
+                },
+            )
+        })
+        .await;
+}